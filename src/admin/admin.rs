@@ -0,0 +1,178 @@
+use anyhow::{bail, Context};
+use reqwest::{Client as ReqwestClient, Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use chatter::common::config::Config;
+use chatter::common::*;
+
+const USAGE: &str = "\
+Usage: chatter-admin [--<key> <value>...] <command>
+
+Commands:
+  clients                 lists the connected clients
+  rooms                   lists all rooms, private ones included
+  disconnect <client id>  disconnects the client from the server
+  delete-room <room id>   deletes the room, evicting its members
+  announce <text>         sends the announcement to every connected client
+  reload-config           makes the server read its config again
+  set-password <user> <password>
+                          sets the user's password, ending their sessions
+
+The admin token is taken from the `admin_token` setting, e.g. CHATTER_ADMIN_TOKEN.";
+
+/// HTTP client of the server's admin API
+struct AdminClient {
+    client: ReqwestClient,
+    base_url: String,
+    token: String,
+}
+
+impl AdminClient {
+    fn new(config: &Config) -> anyhow::Result<Self> {
+        let token = match &config.admin_token {
+            Some(token) => token.clone(),
+            None => bail!("no admin_token configured"),
+        };
+        let mut builder = ReqwestClient::builder().use_rustls_tls();
+        if let Some(ca_path) = &config.tls_ca {
+            let ca =
+                std::fs::read(ca_path).with_context(|| format!("cannot read {:?}", ca_path))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&ca)?);
+        }
+        Ok(AdminClient {
+            client: builder.build()?,
+            base_url: config.http_url(),
+            token,
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, self.base_url.clone() + path)
+            .bearer_auth(&self.token)
+    }
+
+    /// Sends the request and parses the reply, or turns the server's `ApiError` into an error
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        let resp = request.send().await.context("cannot reach the server")?;
+        let code = resp.status();
+        let body = resp.bytes().await?;
+        if !code.is_success() {
+            match serde_json::from_slice::<ApiError>(&body) {
+                Ok(e) => bail!("{}", e),
+                Err(_) => bail!("{}", code),
+            }
+        }
+        // Replies without content are read as null
+        let body = if body.is_empty() { &b"null"[..] } else { &body };
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// Fills in the path's `:<name>` parameter
+fn with_param(path: &str, name: &str, value: &str) -> String {
+    path.replace(&format!(":{}", name), value)
+}
+
+async fn run(config: Config, args: Vec<String>) -> anyhow::Result<()> {
+    let admin = AdminClient::new(&config)?;
+    let command = args.first().map(String::as_str);
+    let arg = args.get(1).map(String::as_str);
+    match (command, arg) {
+        (Some("clients"), None) => {
+            let request = admin.request(Method::GET, ADMIN_CLIENTS_ENDPOINT);
+            let clients: Vec<ClientSummary> = admin.send(request).await?;
+            if clients.is_empty() {
+                println!("No clients connected");
+            }
+            for client in clients {
+                let rooms = client
+                    .rooms
+                    .iter()
+                    .map(|room| room.0.as_str())
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}  {} ({}, v{}) in [{}]",
+                    client.uuid.0,
+                    client.name.0,
+                    client.presence,
+                    client.protocol_version,
+                    rooms.join(", ")
+                );
+            }
+        }
+        (Some("rooms"), None) => {
+            let request = admin.request(Method::GET, ADMIN_ROOMS_ENDPOINT);
+            let rooms: Vec<RoomInfo> = admin.send(request).await?;
+            if rooms.is_empty() {
+                println!("No rooms");
+            }
+            for room in rooms {
+                let access = match (room.private, room.has_password) {
+                    (true, _) => ", private",
+                    (false, true) => ", password",
+                    (false, false) => "",
+                };
+                println!(
+                    "{}  {} ({} members{})",
+                    room.uuid.0, room.name.0, room.member_count, access
+                );
+            }
+        }
+        (Some("disconnect"), Some(client_id)) => {
+            let path = with_param(ADMIN_CLIENT_ENDPOINT, "id", client_id);
+            admin
+                .send::<()>(admin.request(Method::DELETE, &path))
+                .await?;
+            println!("Disconnected client {}", client_id);
+        }
+        (Some("delete-room"), Some(room_id)) => {
+            let path = with_param(ADMIN_ROOM_ENDPOINT, "id", room_id);
+            admin
+                .send::<()>(admin.request(Method::DELETE, &path))
+                .await?;
+            println!("Deleted room {}", room_id);
+        }
+        (Some("announce"), Some(_)) => {
+            let contents = args[1..].join(" ");
+            let request = admin
+                .request(Method::POST, ADMIN_ANNOUNCEMENTS_ENDPOINT)
+                .json(&json!({ "contents": contents }));
+            admin.send::<()>(request).await?;
+            println!("Announced");
+        }
+        (Some("reload-config"), None) => {
+            let request = admin.request(Method::POST, ADMIN_RELOAD_CONFIG_ENDPOINT);
+            let reloaded: Value = admin.send(request).await?;
+            println!("Reloaded the config");
+            if let Some(keys) = reloaded["restart_required"].as_array() {
+                if !keys.is_empty() {
+                    let keys = keys.iter().filter_map(Value::as_str).collect::<Vec<_>>();
+                    println!("Restart the server to apply: {}", keys.join(", "));
+                }
+            }
+        }
+        (Some("set-password"), Some(name)) if args.len() == 3 => {
+            let request = admin
+                .request(Method::POST, ADMIN_PASSWORDS_ENDPOINT)
+                .json(&json!({ "name": name, "password": args[2] }));
+            admin.send::<()>(request).await?;
+            println!("Set the password of {}", name);
+        }
+        _ => bail!("{}", USAGE),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let (config, args) = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {:#}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = run(config, args).await {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
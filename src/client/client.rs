@@ -1,26 +1,101 @@
 use anyhow::Context;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
 use std::io::stdin;
-use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use chatter::common::config::Config;
+use chatter::common::protocol::{
+    self, VersionedReq, CAP_FULL_DUPLEX, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use chatter::common::{ReqData::*, *};
 use futures::{SinkExt, StreamExt};
-use hyper::StatusCode;
 use reqwest::{Client as ReqwestClient, Response};
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use tokio::io::AsyncBufReadExt;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinError, JoinHandle};
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::{
+    connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
+};
 use tungstenite::protocol::Message as TungsteniteMsg;
 use uuid::Uuid;
 
 const CMD_EXIT: &str = "/exit"; // exits the entire app
 const CMD_LOBBY: &str = "/lobby"; // goes back to the lobby
+const CMD_MORE: &str = "/more"; // loads earlier messages of the current room
+const CMD_ROOMS: &str = "/rooms"; // lists the rooms, in the lobby
+const CMD_DESCRIBE: &str = "/describe"; // sets the description of the current room
+const CMD_WHO: &str = "/who"; // lists the members of the current room
+const CMD_RENAME: &str = "/rename"; // renames the current room, for its owner
+const CMD_DELETE: &str = "/delete"; // deletes the current room, for its owner
+const CMD_MSG: &str = "/msg"; // sends a private message, from the lobby or a room
+const CMD_PRIVATE: &str = "/private"; // makes the current room private or public, for its owner
+const CMD_PASSWORD: &str = "/password"; // sets or removes the current room's password, for its owner
+const CMD_INVITE: &str = "/invite"; // invites a user to the current room, for its owner
+const CMD_UNINVITE: &str = "/uninvite"; // takes back an invitation, for the room's owner
+const CMD_KICK: &str = "/kick"; // removes a user from the current room, for moderators
+const CMD_BAN: &str = "/ban"; // bans a user from the current room for some minutes, for moderators
+const CMD_MUTE: &str = "/mute"; // mutes a user in the current room for some minutes, for moderators
+const CMD_MOD: &str = "/mod"; // makes a user a moderator of the current room, for its owner
+const CMD_UNMOD: &str = "/unmod"; // makes a moderator a plain member again, for the room's owner
+const CMD_RETENTION: &str = "/retention"; // sets how much of the current room's log is kept, for its owner
 
 type WSStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
+/// HTTP client bound to the server's address
+#[derive(Clone)]
+struct HttpClient {
+    client: ReqwestClient,
+    base_url: String,
+}
+
+impl HttpClient {
+    fn new(config: &Config) -> anyhow::Result<Self> {
+        let mut builder = ReqwestClient::builder().use_rustls_tls();
+        if let Some(ca_path) = &config.tls_ca {
+            let ca =
+                std::fs::read(ca_path).with_context(|| format!("cannot read {:?}", ca_path))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&ca)?);
+        }
+        Ok(HttpClient {
+            client: builder.build()?,
+            base_url: config.http_url(),
+        })
+    }
+}
+
+/// TLS settings for the WS connection: the usual roots plus the configured CA, if any.
+fn ws_connector(config: &Config) -> anyhow::Result<Option<Connector>> {
+    let ca_path = match (&config.tls_ca, config.tls) {
+        (Some(ca_path), true) => ca_path,
+        _ => return Ok(None),
+    };
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let ca = std::fs::read(ca_path).with_context(|| format!("cannot read {:?}", ca_path))?;
+    for cert in rustls_pemfile::certs(&mut ca.as_slice())? {
+        roots.add(&rustls::Certificate(cert))?;
+    }
+    let tls_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Some(Connector::Rustls(Arc::new(tls_config))))
+}
+
 fn print_greeting() {
     println!("==========================");
     println!("=   Welcome to Chatter   =");
@@ -32,7 +107,7 @@ fn get_line(prompt: &str) -> Result<String, std::io::Error> {
     println!("{}", prompt);
     let mut line = String::new();
     match stdin().read_line(&mut line) {
-        Ok(_) => return Ok(line.trim().to_string()),
+        Ok(_) => Ok(line.trim().to_string()),
         Err(err) => Err(err),
     }
 }
@@ -41,7 +116,7 @@ fn get_nonempty_line(what: &str) -> String {
     let prompt = format!("Enter {}", what);
     let invalid = format!("Invalid {}. Please try again", what);
     loop {
-        match get_line(&*prompt) {
+        match get_line(&prompt) {
             Ok(res) => {
                 if res.trim_end_matches('\n').is_empty() || res == SERVER_SIGNATURE {
                     println!("{}", invalid);
@@ -54,163 +129,525 @@ fn get_nonempty_line(what: &str) -> String {
     }
 }
 
-fn get_header<'a, T>(resp: &'a Response, header: &'a str) -> anyhow::Result<T>
-where
-    T: serde::Deserialize<'a>,
-{
-    let header_value = resp
-        .headers()
-        .get(header)
-        .context(format!("No {}  was found in the request!", header))?;
-    Ok(serde_json::from_slice(header_value.as_bytes())?)
-}
-
-async fn post<T>(
-    reqwest_client: &ReqwestClient,
-    endpoint: &str,
-    body: &T,
-) -> anyhow::Result<Response>
+async fn post<T>(reqwest_client: &HttpClient, endpoint: &str, body: &T) -> anyhow::Result<Response>
 where
     T: ?Sized + serde::Serialize,
 {
     let data = serde_json::to_string(&body)?;
     let resp = reqwest_client
-        .post("http://".to_string() + &get_addr_str(Protocol::HTTP) + endpoint)
+        .client
+        .post(reqwest_client.base_url.clone() + endpoint)
         .body(data)
         .send()
         .await?;
     Ok(resp)
 }
 
-async fn login(reqwest_client: &ReqwestClient, client_name: &str) -> anyhow::Result<Option<Uuid>> {
-    let body = LoginData(ClientName(client_name.to_string()));
-    let resp = post(reqwest_client, LOGIN_ENDPOINT, &body).await?;
-    get_header(&resp, CLIENT_UUID_HEADER)
+/// Why a request failed: the server refused it, or no proper reply came back
+#[derive(Debug)]
+enum RequestError {
+    Server(ApiError),
+    Transport(anyhow::Error),
 }
 
-async fn register(
-    reqwest_client: &ReqwestClient,
-    ws_stream: &mut WSStream,
-    client_name: &str,
-) -> Uuid {
-    let fail_msg = "Error in registration!";
-    let body = RegistrationData(ClientName(client_name.to_string()));
-    let body = serde_json::to_string(&body).unwrap();
-    ws_stream
-        .send(TungsteniteMsg::Text(body))
-        .await
-        .expect(fail_msg);
-    login(reqwest_client, client_name)
+type RequestResult<T> = Result<T, RequestError>;
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Server(e) => write!(f, "{}", e.message),
+            RequestError::Transport(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<anyhow::Error> for RequestError {
+    fn from(e: anyhow::Error) -> Self {
+        RequestError::Transport(e)
+    }
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(e: reqwest::Error) -> Self {
+        RequestError::Transport(e.into())
+    }
+}
+
+/// Turns error replies into errors
+fn reply_result(reply: ReplyData) -> RequestResult<ReplyData> {
+    match reply {
+        ReplyData::Error(e) => Err(RequestError::Server(e)),
+        reply => Ok(reply),
+    }
+}
+
+/// Reads the reply out of the response's body
+async fn http_reply(resp: Response) -> RequestResult<ReplyData> {
+    let code = resp.status();
+    let body = resp.bytes().await?;
+    match serde_json::from_slice(&body) {
+        Ok(reply) => reply_result(reply),
+        // Not a reply of the server, e.g. an error page of a proxy
+        Err(_) if !code.is_success() => Err(RequestError::Server(ApiError::new(
+            ErrorCode::from_status(code),
+            code.to_string(),
+        ))),
+        Err(e) => Err(RequestError::Transport(e.into())),
+    }
+}
+
+type WSIncoming = mpsc::UnboundedReceiver<Result<TungsteniteMsg, tungstenite::Error>>;
+
+/// Sends frames over the WS connection and matches replies to requests by their ids
+#[derive(Clone)]
+struct WsClient {
+    sink: mpsc::UnboundedSender<TungsteniteMsg>,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<ReplyData>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl WsClient {
+    /// Splits the connection between two tasks: one sending frames and one receiving them.
+    /// Received frames that are not replies to requests go to the returned channel.
+    fn spawn(ws_stream: WSStream) -> (Self, WSIncoming) {
+        let (mut ws_sink, mut ws_source) = ws_stream.split();
+        let (sink, mut outgoing) = mpsc::unbounded_channel::<TungsteniteMsg>();
+        let (incoming_sender, incoming) = mpsc::unbounded_channel();
+        let pending: Arc<Mutex<HashMap<u64, oneshot::Sender<ReplyData>>>> = Default::default();
+
+        tokio::spawn(async move {
+            while let Some(msg) = outgoing.recv().await {
+                if ws_sink.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let pending_replies = pending.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = ws_source.next().await {
+                if let Ok(TungsteniteMsg::Text(json_str)) = &msg {
+                    if let Ok(WsReply { id, data }) = serde_json::from_str(json_str) {
+                        if let Some(reply_sender) = pending_replies.lock().unwrap().remove(&id) {
+                            let _ = reply_sender.send(data);
+                        }
+                        continue;
+                    }
+                }
+                if incoming_sender.send(msg).is_err() {
+                    break;
+                }
+            }
+            // Fail the requests still waiting for replies
+            pending_replies.lock().unwrap().clear();
+        });
+
+        let ws_client = WsClient {
+            sink,
+            pending,
+            next_id: Arc::new(AtomicU64::new(0)),
+        };
+        (ws_client, incoming)
+    }
+
+    /// Sends the request as a plain frame, without waiting for a reply
+    fn send(&self, data: ReqData) -> anyhow::Result<()> {
+        let frame = serde_json::to_string(&VersionedReq::new(data))?;
+        self.sink
+            .send(TungsteniteMsg::Text(frame))
+            .context("WS connection closed")
+    }
+
+    async fn request(&self, data: ReqData) -> anyhow::Result<ReplyData> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply_sender, reply) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, reply_sender);
+        let frame = serde_json::to_string(&WsRequest { id, data })?;
+        self.sink
+            .send(TungsteniteMsg::Text(frame))
+            .context("WS connection closed")?;
+        reply.await.context("WS connection closed")
+    }
+
+    fn close(&self) -> anyhow::Result<()> {
+        self.sink
+            .send(TungsteniteMsg::Close(None))
+            .context("WS connection closed")
+    }
+}
+
+/// Sends requests over HTTP or, in full-duplex mode, over the WS connection
+#[derive(Clone)]
+struct Api {
+    http: HttpClient,
+    ws: WsClient,
+    /// Whether the server replies to requests sent over WS
+    ws_requests: bool,
+    full_duplex: bool,
+}
+
+impl Api {
+    async fn request(&self, endpoint: &str, data: ReqData) -> RequestResult<ReplyData> {
+        if self.full_duplex {
+            reply_result(self.ws.request(data).await?)
+        } else {
+            http_reply(post(&self.http, endpoint, &VersionedReq::new(data)).await?).await
+        }
+    }
+}
+
+/// Negotiates the protocol version and capabilities with the server, over HTTP first:
+/// servers from before the negotiation reply there right away, where over WS they wouldn't reply.
+/// The WS connection is then told the version too, which its events are encoded for.
+async fn hello(http: &HttpClient, ws: &WsClient) -> (u32, Vec<String>) {
+    let legacy = (LEGACY_PROTOCOL_VERSION, Vec::new());
+    let body = VersionedReq::new(HelloData(PROTOCOL_VERSION, protocol::capabilities()));
+    let reply = match post(http, HELLO_ENDPOINT, &body).await {
+        Ok(resp) => http_reply(resp).await,
+        Err(e) => Err(e.into()),
+    };
+    if !matches!(reply, Ok(ReplyData::Hello(..))) {
+        return legacy;
+    }
+    match ws
+        .request(HelloData(PROTOCOL_VERSION, protocol::capabilities()))
         .await
-        .expect(fail_msg)
-        .expect(fail_msg)
+    {
+        Ok(ReplyData::Hello(version, capabilities)) => (version, capabilities),
+        _ => legacy,
+    }
+}
+
+fn unexpected() -> RequestError {
+    RequestError::Transport(anyhow::anyhow!("unexpected reply from the server"))
 }
 
-async fn get_room(reqwest_client: &ReqwestClient, room_name: &str) -> anyhow::Result<Option<Uuid>> {
-    let body = GetRoomData(RoomName(room_name.to_string()));
-    let resp = post(reqwest_client, GET_ROOM_ENDPOINT, &body).await?;
-    get_header(&resp, ROOM_UUID_HEADER)
+fn ok(reply: ReplyData) -> RequestResult<()> {
+    match reply {
+        ReplyData::Ok => Ok(()),
+        _ => Err(unexpected()),
+    }
 }
 
-async fn create_room(reqwest_client: &ReqwestClient, room_name: &str) -> anyhow::Result<Uuid> {
-    let body = CreateRoomData(RoomName(room_name.to_string()));
-    let resp = post(reqwest_client, CREATE_ROOM_ENDPOINT, &body).await?;
-    get_header(&resp, ROOM_UUID_HEADER)
+async fn login(api: &Api, client_name: &str, password: &str) -> RequestResult<SessionToken> {
+    let body = LoginData(
+        ClientName(client_name.to_string()),
+        Password(password.to_string()),
+    );
+    match api.request(LOGIN_ENDPOINT, body).await? {
+        ReplyData::SessionToken(token) => Ok(token),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn register(api: &Api, client_name: &str, password: &str) -> RequestResult<SessionToken> {
+    let body = RegistrationData(
+        ClientName(client_name.to_string()),
+        Password(password.to_string()),
+    );
+    if !api.ws_requests {
+        return Err(RequestError::Transport(anyhow::anyhow!(
+            "the server is too old to register users with a password"
+        )));
+    }
+    // The server replies once the user is registered, with their session
+    match reply_result(api.ws.request(body).await?)? {
+        ReplyData::Registered(_, token) => Ok(token),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Attaches the WS connection to an already existing session
+async fn connect(api: &Api, token: &SessionToken) -> RequestResult<()> {
+    let body = ConnectData(token.clone());
+    if api.ws_requests {
+        reply_result(api.ws.request(body).await?).and_then(ok)
+    } else {
+        Ok(api.ws.send(body)?)
+    }
+}
+
+/// Returns the room and whether it was created
+async fn get_or_create_room(
+    api: &Api,
+    token: &SessionToken,
+    room_name: &str,
+) -> RequestResult<(Uuid, bool)> {
+    let body = GetOrCreateRoomData(token.clone(), RoomName(room_name.to_string()));
+    match api.request(GET_OR_CREATE_ROOM_ENDPOINT, body).await? {
+        ReplyData::Room(room_uuid, created) => Ok((room_uuid.0, created)),
+        _ => Err(unexpected()),
+    }
 }
 
 async fn join_room(
-    reqwest_client: &ReqwestClient,
-    client_uuid: Uuid,
-    client_name: &str,
+    api: &Api,
+    token: &SessionToken,
     room_uuid: Uuid,
-) -> anyhow::Result<bool> {
-    let body = JoinRoomData(
-        ClientName(client_name.to_string()),
-        ClientUuid(client_uuid),
+    password: Option<String>,
+) -> RequestResult<bool> {
+    let body = match password {
+        None => JoinRoomData(token.clone(), RoomUuid(room_uuid)),
+        Some(password) => {
+            JoinProtectedRoomData(token.clone(), RoomUuid(room_uuid), Password(password))
+        }
+    };
+    match api.request(JOIN_ROOM_ENDPOINT, body).await? {
+        ReplyData::Success(success) => Ok(success),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn send_msg(
+    api: &Api,
+    token: &SessionToken,
+    msg: ChatMessage,
+    room_uuid: Uuid,
+) -> RequestResult<()> {
+    let body = SendMsgData(token.clone(), msg, RoomUuid(room_uuid));
+    ok(api.request(SEND_MSG_ENDPOINT, body).await?)
+}
+
+async fn leave_room(api: &Api, token: &SessionToken, room_uuid: Uuid) -> RequestResult<()> {
+    let body = LeaveRoomData(token.clone(), RoomUuid(room_uuid));
+    ok(api.request(LEAVE_ROOM_ENDPOINT, body).await?)
+}
+
+async fn load_history(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    before: HistoryCursor,
+) -> RequestResult<()> {
+    let body = LoadHistoryPageData(token.clone(), RoomUuid(room_uuid), before);
+    ok(api.request(LOAD_HISTORY_ENDPOINT, body).await?)
+}
+
+async fn list_rooms(api: &Api, token: &SessionToken) -> RequestResult<Vec<RoomInfo>> {
+    let body = ListRoomsData(token.clone());
+    match api.request(LIST_ROOMS_ENDPOINT, body).await? {
+        ReplyData::Rooms(rooms) => Ok(rooms),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn describe_room(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    description: &str,
+) -> RequestResult<()> {
+    let body = DescribeRoomData(token.clone(), RoomUuid(room_uuid), description.to_string());
+    ok(api.request(DESCRIBE_ROOM_ENDPOINT, body).await?)
+}
+
+async fn list_members(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+) -> RequestResult<Vec<MemberInfo>> {
+    let body = ListMembersData(token.clone(), RoomUuid(room_uuid));
+    match api.request(LIST_MEMBERS_ENDPOINT, body).await? {
+        ReplyData::Members(members) => Ok(members),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn rename_room(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    room_name: &str,
+) -> RequestResult<()> {
+    let body = RenameRoomData(
+        token.clone(),
         RoomUuid(room_uuid),
+        RoomName(room_name.to_string()),
     );
-    let resp = post(reqwest_client, JOIN_ROOM_ENDPOINT, &body).await?;
-    get_header(&resp, SUCCESS_HEADER)
+    ok(api.request(RENAME_ROOM_ENDPOINT, body).await?)
 }
 
-async fn send_msg(
-    reqwest_client: &ReqwestClient,
+async fn delete_room(api: &Api, token: &SessionToken, room_uuid: Uuid) -> RequestResult<()> {
+    let body = DeleteRoomData(token.clone(), RoomUuid(room_uuid));
+    ok(api.request(DELETE_ROOM_ENDPOINT, body).await?)
+}
+
+async fn direct_msg(
+    api: &Api,
+    token: &SessionToken,
+    recipient: &str,
     msg: ChatMessage,
+) -> RequestResult<()> {
+    let body = DirectMsgData(token.clone(), ClientName(recipient.to_string()), msg);
+    ok(api.request(DIRECT_MSG_ENDPOINT, body).await?)
+}
+
+async fn set_room_private(
+    api: &Api,
+    token: &SessionToken,
     room_uuid: Uuid,
-) -> anyhow::Result<Response> {
-    let body = SendMsgData(msg, RoomUuid(room_uuid));
-    post(reqwest_client, SEND_MSG_ENDPOINT, &body).await
+    private: bool,
+) -> RequestResult<()> {
+    let body = SetRoomPrivateData(token.clone(), RoomUuid(room_uuid), private);
+    ok(api.request(SET_ROOM_PRIVATE_ENDPOINT, body).await?)
 }
 
-async fn leave_room(
-    reqwest_client: &ReqwestClient,
-    client_uuid: Uuid,
+async fn set_retention(
+    api: &Api,
+    token: &SessionToken,
     room_uuid: Uuid,
-) -> anyhow::Result<Response> {
-    let body = LeaveRoomData(RoomUuid(room_uuid), ClientUuid(client_uuid));
-    post(reqwest_client, LEAVE_ROOM_ENDPOINT, &body).await
+    retention: Option<RetentionPolicy>,
+) -> RequestResult<()> {
+    let body = SetRetentionData(token.clone(), RoomUuid(room_uuid), retention);
+    ok(api.request(SET_RETENTION_ENDPOINT, body).await?)
 }
 
-async fn exit_app(reqwest_client: &ReqwestClient, client_uuid: Uuid) -> anyhow::Result<Response> {
-    let body = ExitAppData(ClientUuid(client_uuid));
-    post(reqwest_client, EXIT_APP_ENDPOINT, &body).await
+async fn set_room_password(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    password: Option<&str>,
+) -> RequestResult<()> {
+    let password = password.map(|password| Password(password.to_string()));
+    let body = SetRoomPasswordData(token.clone(), RoomUuid(room_uuid), password);
+    ok(api.request(SET_ROOM_PASSWORD_ENDPOINT, body).await?)
 }
 
-async fn register_or_login(
-    reqwest_client: &ReqwestClient,
-    ws_stream: &mut WSStream,
-) -> (String, Uuid) {
-    let client_name = get_nonempty_line("username");
-    let client_uuid = match login(reqwest_client, &client_name)
-        .await
-        .expect("Error during login!")
-    {
-        Some(uuid) => {
-            println!("Welcome back, {}", &client_name);
-            uuid
-        }
-        None => {
-            println!("Nice to meet you, {}", &client_name);
-            register(reqwest_client, ws_stream, &client_name).await
-        }
-    };
-    (client_name, client_uuid)
+async fn set_invited(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    invitee: &str,
+    invited: bool,
+) -> RequestResult<()> {
+    let invitee = ClientName(invitee.to_string());
+    if invited {
+        let body = InviteData(token.clone(), RoomUuid(room_uuid), invitee);
+        ok(api.request(INVITE_ENDPOINT, body).await?)
+    } else {
+        let body = UninviteData(token.clone(), RoomUuid(room_uuid), invitee);
+        ok(api.request(UNINVITE_ENDPOINT, body).await?)
+    }
+}
+
+async fn kick(api: &Api, token: &SessionToken, room_uuid: Uuid, target: &str) -> RequestResult<()> {
+    let body = KickData(
+        token.clone(),
+        RoomUuid(room_uuid),
+        ClientName(target.to_string()),
+    );
+    ok(api.request(KICK_ENDPOINT, body).await?)
+}
+
+async fn ban(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    target: &str,
+    minutes: u32,
+) -> RequestResult<()> {
+    let target = ClientName(target.to_string());
+    let body = BanData(token.clone(), RoomUuid(room_uuid), target, minutes);
+    ok(api.request(BAN_ENDPOINT, body).await?)
+}
+
+async fn mute(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    target: &str,
+    minutes: u32,
+) -> RequestResult<()> {
+    let target = ClientName(target.to_string());
+    let body = MuteData(token.clone(), RoomUuid(room_uuid), target, minutes);
+    ok(api.request(MUTE_ENDPOINT, body).await?)
+}
+
+async fn set_role(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    target: &str,
+    role: Role,
+) -> RequestResult<()> {
+    let target = ClientName(target.to_string());
+    let body = SetRoleData(token.clone(), RoomUuid(room_uuid), target, role);
+    ok(api.request(SET_ROLE_ENDPOINT, body).await?)
+}
+
+async fn exit_app(api: &Api, token: &SessionToken) -> RequestResult<()> {
+    let body = ExitAppData(token.clone());
+    ok(api.request(EXIT_APP_ENDPOINT, body).await?)
 }
 
-async fn try_get_room(reqwest_client: &ReqwestClient, room_name: &str) -> anyhow::Result<Uuid> {
-    let room_uuid = get_room(reqwest_client, room_name).await?;
-    match room_uuid {
-        None => {
-            let res = create_room(reqwest_client, room_name).await?;
-            println!("Created room '{}'", &room_name);
-            Ok(res)
+async fn register_or_login(api: &Api) -> (String, SessionToken) {
+    loop {
+        // Names are only checked by the server, when registering, so that users registered before
+        // the rules can still log in
+        let client_name = get_nonempty_line("username");
+        let password = get_nonempty_line("password");
+        // Logging in doesn't tell unknown names from wrong passwords, registering does
+        match register(api, &client_name, &password).await {
+            Ok(token) => {
+                println!("Nice to meet you, {}", &client_name);
+                return (client_name, token);
+            }
+            Err(RequestError::Server(ApiError {
+                code: ErrorCode::AlreadyExists,
+                ..
+            })) => {}
+            Err(e) => {
+                eprintln!("Error in registration: {}. Please try again.", e);
+                continue;
+            }
+        }
+        match login(api, &client_name, &password).await {
+            Ok(token) => match connect(api, &token).await {
+                Ok(()) => {
+                    println!("Welcome back, {}", &client_name);
+                    return (client_name, token);
+                }
+                Err(e) => eprintln!("Error connecting to the server: {}. Please try again.", e),
+            },
+            Err(RequestError::Server(ApiError {
+                code: ErrorCode::Unauthorized,
+                ..
+            })) => eprintln!("Wrong password. Please try again."),
+            Err(e) => eprintln!("Error during login: {}. Please try again.", e),
         }
-        Some(room_uuid) => Ok(room_uuid),
     }
 }
 
-async fn keep_alive(client_uuid: Uuid) {
-    const HEARTBEAT_TIMEOUT: u64 = 2000;
-    let heartbeat_data = HeartbeatData(ClientUuid(client_uuid));
-    let heartbeat_str = serde_json::to_string(&heartbeat_data).expect("Parsing heartbeat failed");
-    let client = ReqwestClient::new();
+async fn try_get_room(api: &Api, token: &SessionToken, room_name: &str) -> RequestResult<Uuid> {
+    let (room_uuid, created) = get_or_create_room(api, token, room_name).await?;
+    if created {
+        println!("Created room '{}'", &room_name);
+    }
+    Ok(room_uuid)
+}
 
+/// Sends heartbeats until one fails, e.g. once the session was ended, and returns why
+async fn keep_alive(api: Api, token: SessionToken, heartbeat_interval_ms: u64) -> RequestError {
     loop {
-        thread::sleep(Duration::from_millis(HEARTBEAT_TIMEOUT));
-        let heartbeat_str = heartbeat_str.clone();
+        tokio::time::sleep(Duration::from_millis(heartbeat_interval_ms)).await;
 
-        let resp = client
-            .post("http://".to_string() + &get_addr_str(Protocol::HTTP) + HEARTBEAT_ENDPOINT)
-            .body(heartbeat_str)
-            .send()
-            .await
-            .expect("Heartbeat request failed! Closing app...");
-        if resp.status() != StatusCode::OK {
-            panic!("Heartbeat request failed! Closing app...");
+        let reply = api
+            .request(HEARTBEAT_ENDPOINT, HeartbeatData(token.clone()))
+            .await;
+        if let Err(e) = reply.and_then(ok) {
+            return e;
         }
     }
 }
 
+fn report_keep_alive_end(result: Result<RequestError, JoinError>) {
+    match result {
+        Ok(e) => eprintln!("Heartbeat request failed ({}), closing the app...", e),
+        Err(_) => eprintln!("Heartbeats stopped, closing the app..."),
+    }
+}
+
 async fn stdin_loop_for_room() -> (JoinHandle<()>, ReceiverStream<String>) {
     let (tx_stdin, rx) = mpsc::channel::<String>(1);
     let rx = ReceiverStream::new(rx);
@@ -218,8 +655,14 @@ async fn stdin_loop_for_room() -> (JoinHandle<()>, ReceiverStream<String>) {
         loop {
             let mut line = String::new();
             let mut buf_stdin = tokio::io::BufReader::new(tokio::io::stdin());
-            buf_stdin.read_line(&mut line).await.unwrap();
-            tx_stdin.send(line.trim().to_string()).await.unwrap();
+            // The room loop exits once the channel closes
+            match buf_stdin.read_line(&mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if tx_stdin.send(line.trim().to_string()).await.is_err() {
+                break;
+            }
             if line == CMD_LOBBY {
                 break;
             }
@@ -228,74 +671,534 @@ async fn stdin_loop_for_room() -> (JoinHandle<()>, ReceiverStream<String>) {
     (tokio::task::spawn(stdin_loop), rx)
 }
 
-fn check_resp<E>(resp: Result<Response, E>, action: &str) {
-    if let Ok(resp) = resp {
-        if resp.status() != StatusCode::OK {
-            panic!("{} failed!", action);
+/// Returns the argument of the command, if the line is that command
+fn command_arg<'a>(line: &'a str, cmd: &str) -> Option<&'a str> {
+    match line.strip_prefix(cmd) {
+        Some(arg) if arg.is_empty() || arg.starts_with(' ') => Some(arg.trim()),
+        _ => None,
+    }
+}
+
+/// Sends the private message of a `/msg <user> <text>` command, and echoes it
+async fn msg_command(api: &Api, token: &SessionToken, client_name: &str, arg: &str) {
+    match arg.split_once(' ') {
+        Some((recipient, contents)) if !contents.trim().is_empty() => {
+            let msg = ChatMessage::new(client_name, contents.trim());
+            match direct_msg(api, token, recipient, msg.clone()).await {
+                Ok(()) => println!("[{}] YOU -> {}: {}", msg.timestamp, recipient, msg.contents),
+                Err(e) => eprintln!("Error sending private message: {}", e),
+            }
         }
+        _ => eprintln!("Usage: {} <user> <text>", CMD_MSG),
     }
 }
 
-fn receive_msg<E>(client_name: &str, msg: Option<Result<TungsteniteMsg, E>>) {
-    match msg {
-        Some(msg) => match msg {
-            Ok(msg) => match msg {
-                TungsteniteMsg::Text(json_str) => {
-                    let mut msg = serde_json::from_str::<ChatMessage>(&json_str).unwrap();
-                    if msg.author == client_name {
-                        msg.author = String::from("YOU");
-                    }
-                    println!("{}", msg);
+/// Parses the `<user> <minutes>` argument of `/ban` and `/mute`
+fn user_and_minutes(arg: &str) -> Option<(&str, u32)> {
+    let (user, minutes) = arg.split_once(' ')?;
+    Some((user, minutes.trim().parse().ok()?))
+}
+
+/// Parses the `<days> <messages>` argument of `/retention`, 0 meaning no limit, or `default`.
+/// Returns `Some(None)` to go back to the server's default policy.
+fn retention_policy(arg: &str) -> Option<Option<RetentionPolicy>> {
+    if arg == "default" {
+        return Some(None);
+    }
+    let (days, messages) = arg.split_once(' ')?;
+    let days: u32 = days.parse().ok()?;
+    let messages: usize = messages.trim().parse().ok()?;
+    Some(Some(RetentionPolicy {
+        max_age_days: Some(days).filter(|days| *days > 0),
+        max_messages: Some(messages).filter(|messages| *messages > 0),
+    }))
+}
+
+/// What a line typed in a room asks for
+enum RoomCommand<'a> {
+    Exit,
+    Lobby,
+    More,
+    Who,
+    Describe(&'a str),
+    Msg(&'a str),
+    Private(bool),
+    Retention(Option<RetentionPolicy>),
+    /// `None` removes the password
+    Password(Option<&'a str>),
+    Invite(&'a str),
+    Uninvite(&'a str),
+    Kick(&'a str),
+    Ban(&'a str, u32),
+    Mute(&'a str, u32),
+    SetRole(&'a str, Role),
+    Rename(&'a str),
+    Delete,
+    /// A command with a wrong argument, and how to use it
+    Usage(String),
+    Message(&'a str),
+}
+
+impl<'a> RoomCommand<'a> {
+    fn parse(line: &'a str) -> Self {
+        if line == CMD_EXIT {
+            RoomCommand::Exit
+        } else if line == CMD_LOBBY {
+            RoomCommand::Lobby
+        } else if line == CMD_MORE {
+            RoomCommand::More
+        } else if line == CMD_WHO {
+            RoomCommand::Who
+        } else if line == CMD_DELETE {
+            RoomCommand::Delete
+        } else if let Some(description) = command_arg(line, CMD_DESCRIBE) {
+            RoomCommand::Describe(description)
+        } else if let Some(arg) = command_arg(line, CMD_MSG) {
+            RoomCommand::Msg(arg)
+        } else if let Some(arg) = command_arg(line, CMD_PRIVATE) {
+            match arg {
+                "on" => RoomCommand::Private(true),
+                "off" => RoomCommand::Private(false),
+                _ => RoomCommand::Usage(format!("{} on|off", CMD_PRIVATE)),
+            }
+        } else if let Some(arg) = command_arg(line, CMD_RETENTION) {
+            match retention_policy(arg) {
+                Some(retention) => RoomCommand::Retention(retention),
+                None => RoomCommand::Usage(format!(
+                    "{} <days> <messages> (0 for no limit) | default",
+                    CMD_RETENTION
+                )),
+            }
+        } else if let Some(password) = command_arg(line, CMD_PASSWORD) {
+            RoomCommand::Password(Some(password).filter(|password| !password.is_empty()))
+        } else if let Some(invitee) = command_arg(line, CMD_INVITE) {
+            RoomCommand::Invite(invitee)
+        } else if let Some(invitee) = command_arg(line, CMD_UNINVITE) {
+            RoomCommand::Uninvite(invitee)
+        } else if let Some(target) = command_arg(line, CMD_KICK) {
+            RoomCommand::Kick(target)
+        } else if let Some(arg) = command_arg(line, CMD_BAN) {
+            match user_and_minutes(arg) {
+                Some((target, minutes)) => RoomCommand::Ban(target, minutes),
+                None => {
+                    RoomCommand::Usage(format!("{} <user> <minutes> (0 to lift the ban)", CMD_BAN))
                 }
-                _ => eprintln!("Received an invalid type of message"),
-            },
-            Err(_) => panic!("WS server went away!"),
+            }
+        } else if let Some(arg) = command_arg(line, CMD_MUTE) {
+            match user_and_minutes(arg) {
+                Some((target, minutes)) => RoomCommand::Mute(target, minutes),
+                None => RoomCommand::Usage(format!("{} <user> <minutes> (0 to unmute)", CMD_MUTE)),
+            }
+        } else if let Some(target) = command_arg(line, CMD_MOD) {
+            RoomCommand::SetRole(target, Role::Moderator)
+        } else if let Some(target) = command_arg(line, CMD_UNMOD) {
+            RoomCommand::SetRole(target, Role::Member)
+        } else if let Some(new_name) = command_arg(line, CMD_RENAME) {
+            RoomCommand::Rename(new_name)
+        } else {
+            RoomCommand::Message(line)
+        }
+    }
+}
+
+/// Where the client goes after a command typed in a room
+enum AfterCommand {
+    Stay,
+    Lobby,
+    Exit,
+}
+
+/// Carries out the line typed in the room, a command or a message to send
+async fn handle_room_command(
+    api: &Api,
+    token: &SessionToken,
+    room_uuid: Uuid,
+    line: &str,
+    client_name: &str,
+    oldest_seen: Option<HistoryCursor>,
+) -> AfterCommand {
+    match RoomCommand::parse(line) {
+        RoomCommand::Exit => {
+            if let Err(e) = exit_app(api, token).await {
+                eprintln!("Error exiting: {}", e);
+            }
+            // Already closed if the server went away
+            let _ = api.ws.close();
+            return AfterCommand::Exit;
+        }
+        RoomCommand::Lobby => {
+            // The room may be gone, or the client out of it, the lobby is where they go anyway
+            if let Err(e) = leave_room(api, token, room_uuid).await {
+                eprintln!("Error leaving room: {}", e);
+            }
+            return AfterCommand::Lobby;
+        }
+        RoomCommand::More => match oldest_seen {
+            Some(before) => {
+                println!("--- earlier messages ---");
+                if let Err(e) = load_history(api, token, room_uuid, before).await {
+                    eprintln!("Error loading earlier messages: {}", e);
+                }
+            }
+            None => println!("No earlier messages"),
+        },
+        RoomCommand::Who => match list_members(api, token, room_uuid).await {
+            Ok(members) => print_members(client_name, &members),
+            Err(e) => eprintln!("Error listing members: {}", e),
+        },
+        RoomCommand::Describe(description) => {
+            match describe_room(api, token, room_uuid, description).await {
+                Ok(()) => println!("Room description updated"),
+                Err(e) => eprintln!("Error describing room: {}", e),
+            }
+        }
+        RoomCommand::Msg(arg) => msg_command(api, token, client_name, arg).await,
+        RoomCommand::Private(private) => {
+            match set_room_private(api, token, room_uuid, private).await {
+                Ok(()) => println!(
+                    "The room is now {}",
+                    if private { "private" } else { "public" }
+                ),
+                Err(e) => eprintln!("Error changing the room's visibility: {}", e),
+            }
+        }
+        RoomCommand::Retention(retention) => {
+            match set_retention(api, token, room_uuid, retention).await {
+                Ok(()) => println!("Room retention policy updated"),
+                Err(e) => eprintln!("Error setting the room's retention policy: {}", e),
+            }
+        }
+        RoomCommand::Password(password) => {
+            match set_room_password(api, token, room_uuid, password).await {
+                Ok(()) if password.is_some() => println!("Room password set"),
+                Ok(()) => println!("Room password removed"),
+                Err(e) => eprintln!("Error setting the room's password: {}", e),
+            }
+        }
+        RoomCommand::Invite(invitee) => {
+            match set_invited(api, token, room_uuid, invitee, true).await {
+                Ok(()) => println!("Invited {}", invitee),
+                Err(e) => eprintln!("Error inviting {}: {}", invitee, e),
+            }
+        }
+        RoomCommand::Uninvite(invitee) => {
+            match set_invited(api, token, room_uuid, invitee, false).await {
+                Ok(()) => println!("Took back the invitation of {}", invitee),
+                Err(e) => eprintln!("Error taking back the invitation of {}: {}", invitee, e),
+            }
+        }
+        RoomCommand::Kick(target) => {
+            if let Err(e) = kick(api, token, room_uuid, target).await {
+                eprintln!("Error kicking {}: {}", target, e);
+            }
+        }
+        RoomCommand::Ban(target, minutes) => {
+            if let Err(e) = ban(api, token, room_uuid, target, minutes).await {
+                eprintln!("Error banning {}: {}", target, e);
+            }
+        }
+        RoomCommand::Mute(target, minutes) => {
+            if let Err(e) = mute(api, token, room_uuid, target, minutes).await {
+                eprintln!("Error muting {}: {}", target, e);
+            }
+        }
+        RoomCommand::SetRole(target, role) => {
+            if let Err(e) = set_role(api, token, room_uuid, target, role).await {
+                eprintln!("Error making {} a {}: {}", target, role, e);
+            }
+        }
+        RoomCommand::Rename(new_name) => {
+            if let Err(e) = rename_room(api, token, room_uuid, new_name).await {
+                eprintln!("Error renaming room: {}", e);
+            }
+        }
+        RoomCommand::Delete => {
+            // Members, this client included, are sent back to the lobby by the RoomDeleted event
+            if let Err(e) = delete_room(api, token, room_uuid).await {
+                eprintln!("Error deleting room: {}", e);
+            }
+        }
+        RoomCommand::Usage(usage) => eprintln!("Usage: {}", usage),
+        RoomCommand::Message(contents) => {
+            let msg = ChatMessage::new(client_name, contents);
+            match send_msg(api, token, msg, room_uuid).await {
+                Err(RequestError::Server(ApiError {
+                    code: ErrorCode::Unavailable,
+                    ..
+                })) => {
+                    eprintln!("Server is busy, your message was not sent. Please try again.");
+                }
+                Err(RequestError::Server(e)) if e.code == ErrorCode::Forbidden => {
+                    eprintln!("Your message was not sent: {}", e.message);
+                }
+                Err(e) => eprintln!("Error sending message: {}", e),
+                Ok(()) => {}
+            }
+        }
+    }
+    AfterCommand::Stay
+}
+
+fn print_rooms(rooms: &[RoomInfo]) {
+    if rooms.is_empty() {
+        println!("No rooms yet");
+    }
+    for room in rooms {
+        let last_activity = room.last_activity.map_or("never".to_string(), |time| {
+            time.format("%Y-%m-%d %H:%M").to_string()
+        });
+        let access = match (room.private, room.has_password) {
+            (true, _) => ", private",
+            (false, true) => ", password",
+            (false, false) => "",
+        };
+        println!(
+            "  {} ({} members, last active {}{})",
+            room.name.0, room.member_count, last_activity, access
+        );
+        if !room.description.is_empty() {
+            println!("      {}", room.description);
+        }
+    }
+}
+
+fn print_members(client_name: &str, members: &[MemberInfo]) {
+    for member in members {
+        let you = if is_you(&member.name.0, client_name) {
+            " (you)"
+        } else {
+            ""
+        };
+        let role = match member.role {
+            Role::Member => String::new(),
+            role => format!(", {}", role),
+        };
+        println!("  {}{} - {}{}", member.name.0, you, member.presence, role);
+    }
+}
+
+/// Decodes the received event, if it is one. Fails once the connection is gone.
+fn receive_event<E: Display>(
+    msg: Option<Result<TungsteniteMsg, E>>,
+) -> anyhow::Result<Option<ServerEvent>> {
+    match msg {
+        Some(Ok(TungsteniteMsg::Text(json_str))) => match protocol::decode_event(&json_str) {
+            Ok(event) => return Ok(Some(event)),
+            Err(e) => eprintln!("Received an invalid event: {}", e),
         },
-        None => eprintln!("No message!"),
+        // The stream ends right after
+        Some(Ok(TungsteniteMsg::Close(_))) => {}
+        Some(Ok(_)) => eprintln!("Received an invalid type of message"),
+        Some(Err(e)) => anyhow::bail!("Lost the connection to the server: {}", e),
+        None => anyhow::bail!("The server closed the connection"),
+    }
+    Ok(None)
+}
+
+/// Whether the name is the client's, which they may have typed in another case when logging in
+fn is_you(name: &str, client_name: &str) -> bool {
+    ClientName(name.to_string()).normalized() == ClientName(client_name.to_string()).normalized()
+}
+
+/// Prints the event and returns its timestamp, if it's part of the room's history
+fn print_event(client_name: &str, event: &ServerEvent) -> Option<DateTime<Utc>> {
+    let now = Utc::now();
+    match event {
+        ServerEvent::Message(_, msg) => {
+            let mut msg = msg.clone();
+            if is_you(&msg.author, client_name) {
+                msg.author = String::from("YOU");
+            }
+            println!("{}", msg);
+            return Some(msg.timestamp);
+        }
+        ServerEvent::UserJoined(_, name, timestamp) => {
+            println!("[{}] *** {} has joined the chat", timestamp, name.0);
+            return Some(*timestamp);
+        }
+        ServerEvent::UserLeft(_, name, timestamp) => {
+            println!("[{}] *** {} has left the chat", timestamp, name.0);
+            return Some(*timestamp);
+        }
+        ServerEvent::RoomDeleted(_, name) => {
+            println!("[{}] *** Room '{}' was deleted", now, name.0)
+        }
+        ServerEvent::DirectMessage(msg) => println!(
+            "[{}] {} -> YOU: {}",
+            msg.timestamp, msg.author, msg.contents
+        ),
+        ServerEvent::Muted(_, Some(until)) => {
+            println!("[{}] *** You are muted until {}", now, until)
+        }
+        ServerEvent::Muted(_, None) => println!("[{}] *** You are no longer muted", now),
+        ServerEvent::Invited(room_name, by) => println!(
+            "[{}] *** {} invited you to room '{}'",
+            now, by.0, room_name.0
+        ),
+        ServerEvent::RoomRenamed(_, name) => {
+            println!("[{}] *** The room was renamed to '{}'", now, name.0)
+        }
+        ServerEvent::Announcement(msg) => {
+            let banner = "#".repeat(60);
+            println!("{}", banner);
+            println!("[{}] ANNOUNCEMENT: {}", msg.timestamp, msg.contents);
+            println!("{}", banner);
+        }
+        ServerEvent::Error(error) => eprintln!("[{}] !!! {}", now, error),
+        ServerEvent::Kicked(_, reason) => {
+            println!("[{}] *** You were removed from the room: {}", now, reason)
+        }
     }
+    None
 }
 
-async fn chat_client() {
+/// Tells whether the event means the client is no longer in the room
+fn is_eviction(event: &ServerEvent, room_uuid: Uuid) -> bool {
+    match event {
+        ServerEvent::RoomDeleted(room, _) | ServerEvent::Kicked(room, _) => room.0 == room_uuid,
+        _ => false,
+    }
+}
+
+async fn chat_client(config: Config) {
     print_greeting();
 
-    let reqwest_client = ReqwestClient::new();
-    let (mut ws_stream, _) = connect_async("ws://".to_string() + &get_addr_str(Protocol::WS))
-        .await
-        .expect("Failed to connect to the WS server!");
+    let http = match HttpClient::new(&config) {
+        Ok(http) => http,
+        Err(e) => {
+            eprintln!("Failed to set up the HTTP client: {:#}", e);
+            return;
+        }
+    };
+    let connector = match ws_connector(&config) {
+        Ok(connector) => connector,
+        Err(e) => {
+            eprintln!("Failed to set up TLS for the WS connection: {:#}", e);
+            return;
+        }
+    };
+    let ws_stream = match connect_async_tls_with_config(config.ws_url(), None, connector).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(e) => {
+            eprintln!("Failed to connect to the WS server: {}", e);
+            return;
+        }
+    };
+    let (ws, mut ws_incoming) = WsClient::spawn(ws_stream);
+    let (version, capabilities) = hello(&http, &ws).await;
+    if version < PROTOCOL_VERSION {
+        eprintln!(
+            "The server speaks an older protocol (v{}), some features may not work",
+            version
+        );
+    }
+    let ws_requests = capabilities.iter().any(|c| c == CAP_FULL_DUPLEX);
+    if config.full_duplex && !ws_requests {
+        eprintln!("The server does not support full-duplex mode, falling back to HTTP");
+    }
+    let api = Api {
+        http,
+        ws,
+        ws_requests,
+        full_duplex: config.full_duplex && ws_requests,
+    };
 
-    let (client_name, client_uuid) = register_or_login(&reqwest_client, &mut ws_stream).await;
-    let keep_alive_handle = tokio::spawn(keep_alive(client_uuid));
+    let (client_name, token) = register_or_login(&api).await;
+    let mut keep_alive_handle = tokio::spawn(keep_alive(
+        api.clone(),
+        token.clone(),
+        config.heartbeat_interval_ms,
+    ));
 
+    println!(
+        "Type {} to see the rooms, {} <user> <text> to message someone, {} to quit",
+        CMD_ROOMS, CMD_MSG, CMD_EXIT
+    );
     loop {
+        // Events aren't awaited in the lobby, so those received meanwhile are shown between prompts
+        loop {
+            let ws_msg = match ws_incoming.try_recv() {
+                Ok(ws_msg) => Some(ws_msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => None,
+            };
+            match receive_event(ws_msg) {
+                Ok(Some(event)) => {
+                    print_event(&client_name, &event);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            }
+        }
         let room_name = get_nonempty_line("room name");
+        if keep_alive_handle.is_finished() {
+            report_keep_alive_end(keep_alive_handle.await);
+            return;
+        }
         if room_name == CMD_EXIT {
             return;
         }
-        match try_get_room(&reqwest_client, &room_name).await {
+        if room_name == CMD_ROOMS {
+            match list_rooms(&api, &token).await {
+                Ok(rooms) => print_rooms(&rooms),
+                Err(e) => eprintln!("Error listing rooms: {}", e),
+            }
+            continue;
+        }
+        if let Some(arg) = command_arg(&room_name, CMD_MSG) {
+            msg_command(&api, &token, &client_name, arg).await;
+            continue;
+        }
+        match try_get_room(&api, &token, &room_name).await {
             Ok(room_uuid) => {
-                match join_room(&reqwest_client, client_uuid, &*client_name, room_uuid).await {
+                let mut joined = join_room(&api, &token, room_uuid, None).await;
+                if let Err(RequestError::Server(ApiError {
+                    code: ErrorCode::PasswordRequired,
+                    ..
+                })) = joined
+                {
+                    let password = get_nonempty_line("room password");
+                    joined = join_room(&api, &token, room_uuid, Some(password)).await;
+                }
+                match joined {
                     Ok(true) => {
                         println!("Joined room '{}'", room_name);
                         let (stdin_loop, mut rx) = stdin_loop_for_room().await;
+                        let mut oldest_seen: Option<HistoryCursor> = None;
                         loop {
-                            if keep_alive_handle.is_finished() {
-                                return;
-                            }
                             tokio::select! {
-                                ws_msg = ws_stream.next() => receive_msg(&client_name, ws_msg),
+                                result = &mut keep_alive_handle => {
+                                    report_keep_alive_end(result);
+                                    return;
+                                },
+                                ws_msg = ws_incoming.recv() => {
+                                    match receive_event(ws_msg) {
+                                        Ok(Some(event)) => {
+                                            if let Some(timestamp) = print_event(&client_name, &event) {
+                                                oldest_seen = Some(HistoryCursor::see(oldest_seen, timestamp));
+                                            }
+                                            if is_eviction(&event, room_uuid) {
+                                                println!("Back in the lobby");
+                                                break;
+                                            }
+                                        }
+                                        Ok(None) => {}
+                                        Err(e) => {
+                                            eprintln!("{}", e);
+                                            return;
+                                        }
+                                    }
+                                },
                                 stdin_msg = rx.next() => {
                                     match stdin_msg {
-                                        Some(msg) => {
-                                            let msg = ChatMessage::new(&client_name, &msg);
-                                            if msg.contents == CMD_EXIT {
-                                                ws_stream.close(None).await.expect("Closing ws stream failed!");
-                                                check_resp(exit_app(&reqwest_client, client_uuid).await, "exit_app");
-                                                return;
-                                            } else if msg.contents == CMD_LOBBY {
-                                                check_resp(leave_room(&reqwest_client, client_uuid, room_uuid).await, "leave_room");
-                                                break;
-                                            } else {
-                                                check_resp(send_msg(&reqwest_client, msg, room_uuid).await, "send_msg");
+                                        Some(line) => {
+                                            match handle_room_command(&api, &token, room_uuid, &line, &client_name, oldest_seen).await {
+                                                AfterCommand::Stay => {}
+                                                AfterCommand::Lobby => break,
+                                                AfterCommand::Exit => return,
                                             }
                                         },
                                         None => return
@@ -316,5 +1219,13 @@ async fn chat_client() {
 
 #[tokio::main]
 async fn main() {
-    chat_client().await;
+    let (mut config, args) = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {:#}", e);
+        std::process::exit(1);
+    });
+    config.set_host_arg(&args).unwrap_or_else(|e| {
+        eprintln!("Invalid arguments: {:#}", e);
+        std::process::exit(1);
+    });
+    chat_client(config).await;
 }
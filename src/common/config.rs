@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use super::{RetentionPolicy, WS_ENDPOINT};
+
+const APP_DIR: &str = ".chatter";
+const ROOM_LOGS_DIR: &str = "room_logs";
+const CONFIG_FILE: &str = "config.toml";
+const CONFIG_FLAG: &str = "--config";
+const CONFIG_ENV: &str = "CHATTER_CONFIG";
+const ENV_PREFIX: &str = "CHATTER_";
+
+/// Settings shared by the server and the client. Each of them only uses the relevant part.
+///
+/// Values are taken from (in increasing order of precedence): the defaults, the TOML config file
+/// (`--config <path>`, `$CHATTER_CONFIG` or `~/.chatter/config.toml`), `CHATTER_<KEY>` environment
+/// variables and `--<key>` command-line flags, with dashes instead of underscores.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server binds to / the client connects to
+    pub host: String,
+    pub http_port: u16,
+    pub ws_port: u16,
+    /// Run WebSockets on the HTTP port (under `/ws`) instead of a port of their own
+    pub single_port: bool,
+    /// Serve (server) or connect (client) over TLS, i.e. `https://` and `wss://`
+    pub tls: bool,
+    /// PEM certificate chain and private key of the server, required when `tls` is on
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// PEM certificate the client trusts on top of the usual roots, e.g. a self-signed one
+    pub tls_ca: Option<PathBuf>,
+    /// Send all of the client's requests over the WS connection instead of HTTP
+    pub full_duplex: bool,
+    /// How often the client sends heartbeats
+    pub heartbeat_interval_ms: u64,
+    /// How long the server waits for a heartbeat before it considers the client dead
+    pub kill_timeout_ms: u64,
+    /// Where the server keeps its state
+    pub data_dir: PathBuf,
+    /// Where the server keeps room logs, see `log_dir()`
+    pub log_dir: Option<PathBuf>,
+    /// How many messages are replayed when joining a room or loading more of its history
+    pub history_size: usize,
+    /// How long a room stays empty before the server deletes it, 0 to keep empty rooms forever
+    pub empty_room_timeout_ms: u64,
+    /// Bearer token of the admin API (server) / sent by `chatter-admin`. The API is off without one.
+    pub admin_token: Option<String>,
+    /// Size in bytes past which a room log is rotated
+    pub max_log_size: u64,
+    /// Age of its first message past which a room log is rotated
+    pub max_log_age_days: u32,
+    /// How often the server applies the retention policies and rotates old logs
+    pub log_sweep_interval_ms: u64,
+    /// How many days of messages rooms without a retention policy of their own keep, 0 for all
+    pub retention_days: u32,
+    /// How many messages rooms without a retention policy of their own keep, 0 for all
+    pub retention_messages: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_string(),
+            http_port: 8080,
+            ws_port: 8000,
+            single_port: false,
+            tls: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+            full_duplex: false,
+            heartbeat_interval_ms: 2000,
+            kill_timeout_ms: 5000,
+            data_dir: default_data_dir(),
+            log_dir: None,
+            history_size: 20,
+            empty_room_timeout_ms: 7 * 24 * 60 * 60 * 1000,
+            admin_token: None,
+            max_log_size: 1024 * 1024,
+            max_log_age_days: 7,
+            log_sweep_interval_ms: 60 * 60 * 1000,
+            retention_days: 90,
+            retention_messages: 0,
+        }
+    }
+}
+
+fn default_data_dir() -> PathBuf {
+    dirs::home_dir()
+        .expect("Cannot locate home directory")
+        .join(APP_DIR)
+}
+
+/// Where the config file is looked for when none is given
+fn default_config_path(home_dir: Option<PathBuf>) -> Option<PathBuf> {
+    home_dir.map(|home_dir| home_dir.join(APP_DIR).join(CONFIG_FILE))
+}
+
+impl Config {
+    const KEYS: [&'static str; 21] = [
+        "host",
+        "http_port",
+        "ws_port",
+        "single_port",
+        "tls",
+        "tls_cert",
+        "tls_key",
+        "tls_ca",
+        "full_duplex",
+        "heartbeat_interval_ms",
+        "kill_timeout_ms",
+        "data_dir",
+        "log_dir",
+        "history_size",
+        "empty_room_timeout_ms",
+        "admin_token",
+        "max_log_size",
+        "max_log_age_days",
+        "log_sweep_interval_ms",
+        "retention_days",
+        "retention_messages",
+    ];
+
+    /// Builds the config from the process' arguments and environment.
+    /// Returns it along with the positional (non-flag) arguments.
+    pub fn load() -> anyhow::Result<(Config, Vec<String>)> {
+        Self::load_from(
+            std::env::args().skip(1).collect(),
+            std::env::vars(),
+            dirs::home_dir(),
+        )
+    }
+
+    /// Same as `load`, from the given arguments, environment variables and home directory
+    pub fn load_from(
+        args: Vec<String>,
+        env: impl IntoIterator<Item = (String, String)>,
+        home_dir: Option<PathBuf>,
+    ) -> anyhow::Result<(Config, Vec<String>)> {
+        let (flags, positional) = parse_args(args)?;
+        let env = env
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+            .collect::<HashMap<_, _>>();
+
+        let config_path = flags
+            .iter()
+            .find(|(key, _)| key == CONFIG_FLAG)
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| env.get(CONFIG_ENV).map(PathBuf::from));
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None => match default_config_path(home_dir) {
+                Some(default_path) if default_path.exists() => Self::from_file(&default_path)?,
+                _ => Config::default(),
+            },
+        };
+
+        for key in Self::KEYS {
+            let env_var = ENV_PREFIX.to_string() + &key.to_uppercase();
+            if let Some(value) = env.get(&env_var) {
+                config
+                    .set(key, value)
+                    .with_context(|| format!("invalid value of {}", env_var))?;
+            }
+        }
+        for (flag, value) in flags.iter().filter(|(flag, _)| flag != CONFIG_FLAG) {
+            let key = flag.trim_start_matches("--").replace('-', "_");
+            config
+                .set(&key, value)
+                .with_context(|| format!("invalid value of {}", flag))?;
+        }
+
+        config.validate()?;
+        Ok((config, positional))
+    }
+
+    /// Checks the settings which only make sense together, once every source is merged
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.heartbeat_interval_ms == 0 {
+            bail!("the heartbeat interval must be positive");
+        }
+        if self.kill_timeout_ms <= self.heartbeat_interval_ms {
+            bail!("the kill timeout must be longer than the heartbeat interval");
+        }
+        if self.log_sweep_interval_ms == 0 {
+            bail!("the log sweep interval must be positive");
+        }
+        Ok(())
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("invalid config file {:?}", path))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "host" => self.host = value.to_string(),
+            "http_port" => self.http_port = value.parse()?,
+            "ws_port" => self.ws_port = value.parse()?,
+            "single_port" => self.single_port = value.parse()?,
+            "tls" => self.tls = value.parse()?,
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)),
+            "tls_ca" => self.tls_ca = Some(PathBuf::from(value)),
+            "full_duplex" => self.full_duplex = value.parse()?,
+            "heartbeat_interval_ms" => self.heartbeat_interval_ms = value.parse()?,
+            "kill_timeout_ms" => self.kill_timeout_ms = value.parse()?,
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "log_dir" => self.log_dir = Some(PathBuf::from(value)),
+            "history_size" => self.history_size = value.parse()?,
+            "empty_room_timeout_ms" => self.empty_room_timeout_ms = value.parse()?,
+            "admin_token" => self.admin_token = Some(value.to_string()),
+            "max_log_size" => self.max_log_size = value.parse()?,
+            "max_log_age_days" => self.max_log_age_days = value.parse()?,
+            "log_sweep_interval_ms" => self.log_sweep_interval_ms = value.parse()?,
+            "retention_days" => self.retention_days = value.parse()?,
+            "retention_messages" => self.retention_messages = value.parse()?,
+            _ => bail!("unknown option '{}'", key),
+        }
+        Ok(())
+    }
+
+    /// Where room logs are kept: `log_dir` if it's set, otherwise under the data directory
+    pub fn log_dir(&self) -> PathBuf {
+        self.log_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join(ROOM_LOGS_DIR))
+    }
+
+    /// Takes the host from the only positional argument (e.g. `client 10.0.0.5`), as older
+    /// versions did. Any other positional argument is rejected, so that typos don't go unnoticed.
+    pub fn set_host_arg(&mut self, args: &[String]) -> anyhow::Result<()> {
+        match args {
+            [] => {}
+            [host] => self.host = host.clone(),
+            [_, unexpected @ ..] => bail!("unexpected arguments: {}", unexpected.join(" ")),
+        }
+        Ok(())
+    }
+
+    /// Retention policy of the rooms which have none of their own
+    pub fn default_retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days: Some(self.retention_days).filter(|days| *days > 0),
+            max_messages: Some(self.retention_messages).filter(|count| *count > 0),
+        }
+    }
+
+    pub fn http_addr(&self) -> String {
+        format!("{}:{}", self.host, self.http_port)
+    }
+
+    pub fn ws_addr(&self) -> String {
+        format!("{}:{}", self.host, self.ws_port)
+    }
+
+    pub fn http_url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}", scheme, self.http_addr())
+    }
+
+    pub fn ws_url(&self) -> String {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        if self.single_port {
+            format!("{}://{}{}", scheme, self.http_addr(), WS_ENDPOINT)
+        } else {
+            format!("{}://{}", scheme, self.ws_addr())
+        }
+    }
+}
+
+type Flags = Vec<(String, String)>;
+
+/// Splits the arguments into `--flag value` pairs (`--flag=value` works as well) and positional ones.
+fn parse_args(args: Vec<String>) -> anyhow::Result<(Flags, Vec<String>)> {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+        } else if let Some((flag, value)) = arg.split_once('=') {
+            flags.push((flag.to_string(), value.to_string()));
+        } else {
+            match args.next() {
+                Some(value) => flags.push((arg, value)),
+                None => bail!("missing value for {}", arg),
+            }
+        }
+    }
+    Ok((flags, positional))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::test_dir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Loads the config from the arguments only, with no environment nor home directory
+    fn load(arguments: &[&str]) -> anyhow::Result<(Config, Vec<String>)> {
+        Config::load_from(args(arguments), Vec::new(), None)
+    }
+
+    /// Config file in a fresh directory
+    fn config_file(contents: &str) -> PathBuf {
+        let path = test_dir().join(CONFIG_FILE);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_override_env_which_overrides_the_file() {
+        let path = config_file("http_port = 1001\nws_port = 1002\nkill_timeout_ms = 1003\n");
+        let (config, positional) = Config::load_from(
+            args(&[
+                "--config",
+                path.to_str().unwrap(),
+                "--kill-timeout-ms=3003",
+                "extra",
+            ]),
+            env(&[
+                ("CHATTER_WS_PORT", "2002"),
+                ("CHATTER_KILL_TIMEOUT_MS", "2003"),
+            ]),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(config.http_port, 1001);
+        assert_eq!(config.ws_port, 2002);
+        assert_eq!(config.kill_timeout_ms, 3003);
+        // Not in the file
+        assert_eq!(config.history_size, Config::default().history_size);
+        assert_eq!(positional, ["extra"]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_invalid_values() {
+        let path = config_file("no_such_key = 1\n");
+        assert!(load(&["--config", path.to_str().unwrap()]).is_err());
+        assert!(load(&["--no-such-key", "1"]).is_err());
+        assert!(load(&["--max-log-size", "big"]).is_err());
+        assert!(load(&["--history-size"]).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_zero_intervals_and_timeouts() {
+        assert!(load(&["--log-sweep-interval-ms", "0"]).is_err());
+        assert!(load(&["--heartbeat-interval-ms", "0"]).is_err());
+        assert!(load(&["--kill-timeout-ms", "0"]).is_err());
+    }
+
+    #[test]
+    fn requires_the_kill_timeout_to_exceed_the_heartbeat_interval() {
+        let path = config_file("heartbeat_interval_ms = 3000\n");
+        let config_flag = path.to_str().unwrap();
+        // Each value is fine on its own, only the merged config is wrong
+        assert!(load(&["--config", config_flag, "--kill-timeout-ms", "3000"]).is_err());
+        assert!(Config::load_from(
+            args(&["--config", config_flag]),
+            env(&[("CHATTER_KILL_TIMEOUT_MS", "2000")]),
+            None,
+        )
+        .is_err());
+        let (config, _) = load(&["--config", config_flag, "--kill-timeout-ms", "3001"]).unwrap();
+        assert_eq!(config.kill_timeout_ms, 3001);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn finds_the_config_file_through_the_env_or_the_home_dir() {
+        let path = config_file("history_size = 42\n");
+        let (config, _) = Config::load_from(
+            Vec::new(),
+            env(&[(CONFIG_ENV, path.to_str().unwrap())]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.history_size, 42);
+
+        let home_dir = test_dir();
+        fs::create_dir_all(home_dir.join(APP_DIR)).unwrap();
+        fs::copy(&path, home_dir.join(APP_DIR).join(CONFIG_FILE)).unwrap();
+        let (config, _) =
+            Config::load_from(Vec::new(), Vec::new(), Some(home_dir.clone())).unwrap();
+        assert_eq!(config.history_size, 42);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        fs::remove_dir_all(home_dir).unwrap();
+    }
+
+    #[test]
+    fn log_dir_follows_the_data_dir_unless_set() {
+        let path = config_file("data_dir = \"/srv/chatter\"\n");
+        let config_flag = ["--config", path.to_str().unwrap()];
+        let (config, _) = load(&config_flag).unwrap();
+        assert_eq!(config.log_dir(), Path::new("/srv/chatter/room_logs"));
+
+        let (config, _) =
+            load(&[&config_flag[..], &["--log-dir", "/var/log/chatter"]].concat()).unwrap();
+        assert_eq!(config.log_dir(), Path::new("/var/log/chatter"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn takes_the_host_from_the_only_positional_argument() {
+        let mut config = Config::default();
+        config.set_host_arg(&[]).unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        config.set_host_arg(&args(&["10.0.0.5"])).unwrap();
+        assert_eq!(config.host, "10.0.0.5");
+        assert!(config.set_host_arg(&args(&["10.0.0.5", "8080"])).is_err());
+    }
+}
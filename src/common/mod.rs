@@ -1,18 +1,26 @@
+pub mod config;
+pub mod protocol;
+
 use chrono::{DateTime, Utc};
+use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
 
-type WSSender = UnboundedSender<Result<warp::ws::Message, warp::Error>>;
+pub type WSSender = UnboundedSender<Result<warp::ws::Message, warp::Error>>;
 
 pub const CLIENT_UUID_HEADER: &str = "client_uuid";
 pub const ROOM_UUID_HEADER: &str = "room_uuid";
 pub const SUCCESS_HEADER: &str = "success";
+pub const SESSION_TOKEN_HEADER: &str = "session_token";
 pub const SERVER_SIGNATURE: &str = "SERVER";
 
 pub const HEALTH_CHECK_ENDPOINT: &str = "/health_check";
+pub const HELLO_ENDPOINT: &str = "/hello";
 pub const SEND_MSG_ENDPOINT: &str = "/send_msg";
 pub const LEAVE_ROOM_ENDPOINT: &str = "/leave_room";
 pub const EXIT_APP_ENDPOINT: &str = "/exit_app";
@@ -21,37 +29,286 @@ pub const GET_ROOM_ENDPOINT: &str = "/get_room";
 pub const CREATE_ROOM_ENDPOINT: &str = "/create_room";
 pub const JOIN_ROOM_ENDPOINT: &str = "/join_room";
 pub const HEARTBEAT_ENDPOINT: &str = "/heartbeat";
+pub const LOAD_HISTORY_ENDPOINT: &str = "/load_history";
+pub const LIST_ROOMS_ENDPOINT: &str = "/list_rooms";
+pub const DESCRIBE_ROOM_ENDPOINT: &str = "/describe_room";
+pub const LIST_MEMBERS_ENDPOINT: &str = "/list_members";
+pub const GET_OR_CREATE_ROOM_ENDPOINT: &str = "/get_or_create_room";
+pub const RENAME_ROOM_ENDPOINT: &str = "/rename_room";
+pub const DELETE_ROOM_ENDPOINT: &str = "/delete_room";
+pub const DIRECT_MSG_ENDPOINT: &str = "/direct_msg";
+pub const SET_ROOM_PRIVATE_ENDPOINT: &str = "/set_room_private";
+pub const SET_ROOM_PASSWORD_ENDPOINT: &str = "/set_room_password";
+pub const INVITE_ENDPOINT: &str = "/invite";
+pub const UNINVITE_ENDPOINT: &str = "/uninvite";
+pub const KICK_ENDPOINT: &str = "/kick";
+pub const BAN_ENDPOINT: &str = "/ban";
+pub const MUTE_ENDPOINT: &str = "/mute";
+pub const SET_ROLE_ENDPOINT: &str = "/set_role";
+pub const SET_RETENTION_ENDPOINT: &str = "/set_retention";
+pub const WS_ENDPOINT: &str = "/ws"; // WebSocket upgrade on the HTTP port
 
-pub const ADDR_HTTP: &str = "127.0.0.1:8080";
-pub const ADDR_WS: &str = "127.0.0.1:8000";
-pub const LOCALHOST: &str = "127.0.0.1";
+// REST routes, see the server's `rest` module
+pub const ROOMS_ENDPOINT: &str = "/rooms";
+pub const ROOM_ENDPOINT: &str = "/rooms/:id";
+pub const ROOM_MEMBERS_ENDPOINT: &str = "/rooms/:id/members";
+pub const ROOM_MEMBER_ENDPOINT: &str = "/rooms/:id/members/:client";
+pub const ROOM_MESSAGES_ENDPOINT: &str = "/rooms/:id/messages";
+pub const USER_MESSAGES_ENDPOINT: &str = "/users/:id/messages";
 
-pub const PORT_HTTP: &str = ":8080";
-pub const PORT_WS: &str = ":8000";
+// Admin routes, see the server's `admin` module
+pub const ADMIN_CLIENTS_ENDPOINT: &str = "/admin/clients";
+pub const ADMIN_CLIENT_ENDPOINT: &str = "/admin/clients/:id";
+pub const ADMIN_ROOMS_ENDPOINT: &str = "/admin/rooms";
+pub const ADMIN_ROOM_ENDPOINT: &str = "/admin/rooms/:id";
+pub const ADMIN_ANNOUNCEMENTS_ENDPOINT: &str = "/admin/announcements";
+pub const ADMIN_RELOAD_CONFIG_ENDPOINT: &str = "/admin/reload_config";
+pub const ADMIN_PASSWORDS_ENDPOINT: &str = "/admin/passwords";
 
 #[derive(Serialize, Deserialize, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ClientUuid(pub Uuid);
 #[derive(Serialize, Deserialize, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct RoomUuid(pub Uuid);
-#[derive(Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct ClientName(pub String);
-#[derive(Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct RoomName(pub String);
 
+/// Longest user name, in characters
+pub const MAX_NAME_LEN: usize = 32;
+/// Names the client shows in place of the author's, which no user can take
+const RESERVED_NAMES: [&str; 2] = [SERVER_SIGNATURE, "YOU"];
+
+impl ClientName {
+    /// Form in which user names are compared, so that "Alice" can't pose as "alice"
+    pub fn normalized(&self) -> String {
+        self.0.to_lowercase()
+    }
+
+    /// Checks whether the name can be registered, returning why not otherwise
+    pub fn validate(&self) -> Result<(), String> {
+        let len = self.0.chars().count();
+        if len == 0 || len > MAX_NAME_LEN {
+            return Err(format!(
+                "Names must be 1 to {} characters long",
+                MAX_NAME_LEN
+            ));
+        }
+        if !self
+            .0
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        {
+            return Err("Names can only contain letters, digits, '_', '-' and '.'".to_string());
+        }
+        if RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(&self.0))
+        {
+            return Err(format!("The name '{}' is reserved", self.0));
+        }
+        Ok(())
+    }
+}
+
+impl RoomName {
+    /// Form in which room names are compared, so that "General" and "general " are the same room
+    pub fn normalized(&self) -> String {
+        self.0.trim().to_lowercase()
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct SessionToken(pub String);
+#[derive(Serialize, Deserialize)]
+pub struct Password(pub String);
+
 #[derive(Serialize, Deserialize)]
 pub enum ReqData {
-    HeartbeatData(ClientUuid),
-    CreateRoomData(RoomName),
-    GetRoomData(RoomName),
-    JoinRoomData(ClientName, ClientUuid, RoomUuid),
-    SendMsgData(ChatMessage, RoomUuid),
-    LoginData(ClientName),
-    RegistrationData(ClientName),
-    LeaveRoomData(RoomUuid, ClientUuid),
-    ExitAppData(ClientUuid),
+    HeartbeatData(SessionToken),
+    CreateRoomData(SessionToken, RoomName),
+    GetRoomData(SessionToken, RoomName),
+    JoinRoomData(SessionToken, RoomUuid),
+    SendMsgData(SessionToken, ChatMessage, RoomUuid),
+    LoginData(ClientName, Password),
+    RegistrationData(ClientName, Password),
+    ConnectData(SessionToken),
+    LeaveRoomData(SessionToken, RoomUuid),
+    ExitAppData(SessionToken),
+    /// Loads the messages sent strictly before the given time
+    LoadHistoryData(SessionToken, RoomUuid, DateTime<Utc>),
+    /// Protocol version and capabilities of the client, sent to `HELLO_ENDPOINT` and then over WS
+    /// before anything else
+    HelloData(u32, Vec<String>),
+    ListRoomsData(SessionToken),
+    DescribeRoomData(SessionToken, RoomUuid, String),
+    ListMembersData(SessionToken, RoomUuid),
+    GetOrCreateRoomData(SessionToken, RoomName),
+    RenameRoomData(SessionToken, RoomUuid, RoomName),
+    DeleteRoomData(SessionToken, RoomUuid),
+    /// Private message to the named user
+    DirectMsgData(SessionToken, ClientName, ChatMessage),
+    /// Joins a room which requires a password, sent to `JOIN_ROOM_ENDPOINT` as well
+    JoinProtectedRoomData(SessionToken, RoomUuid, Password),
+    SetRoomPrivateData(SessionToken, RoomUuid, bool),
+    /// Sets the room's password, or removes it
+    SetRoomPasswordData(SessionToken, RoomUuid, Option<Password>),
+    InviteData(SessionToken, RoomUuid, ClientName),
+    UninviteData(SessionToken, RoomUuid, ClientName),
+    KickData(SessionToken, RoomUuid, ClientName),
+    /// Bans the user from the room for the given number of minutes, 0 lifting the ban
+    BanData(SessionToken, RoomUuid, ClientName, u32),
+    /// Mutes the user in the room for the given number of minutes, 0 lifting the mute
+    MuteData(SessionToken, RoomUuid, ClientName, u32),
+    SetRoleData(SessionToken, RoomUuid, ClientName, Role),
+    /// Loads the messages before the cursor, sent to `LOAD_HISTORY_ENDPOINT` as well
+    LoadHistoryPageData(SessionToken, RoomUuid, HistoryCursor),
+    /// Sets how much of the room's log is kept, or goes back to the server's default
+    SetRetentionData(SessionToken, RoomUuid, Option<RetentionPolicy>),
+}
+
+/// Longest room description, in characters
+pub const MAX_DESCRIPTION_LEN: usize = 200;
+
+/// Version of the `ServerEvent` format, bumped whenever it changes incompatibly
+pub const EVENT_VERSION: u32 = 1;
+
+/// Everything the server pushes to clients over WS, apart from replies to their requests
+#[derive(Serialize, Deserialize, Clone)]
+pub enum ServerEvent {
+    Message(RoomUuid, ChatMessage),
+    /// The user joined or left the room at the given time, as logged by the server
+    UserJoined(RoomUuid, ClientName, DateTime<Utc>),
+    UserLeft(RoomUuid, ClientName, DateTime<Utc>),
+    RoomDeleted(RoomUuid, RoomName),
+    /// The room is now known under the given name
+    RoomRenamed(RoomUuid, RoomName),
+    /// Private message, only sent to its recipient
+    DirectMessage(ChatMessage),
+    /// The client was invited to the room by the given user
+    Invited(RoomName, ClientName),
+    /// The client can't send messages to the room until the given time, or can again if none
+    Muted(RoomUuid, Option<DateTime<Utc>>),
+    Error(String),
+    /// The client was removed from the room, for the given reason
+    Kicked(RoomUuid, String),
+    /// Server-wide announcement, sent to every connected client whichever room they are in
+    Announcement(ChatMessage),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventEnvelope {
+    pub version: u32,
+    pub event: ServerEvent,
+}
+
+impl EventEnvelope {
+    pub fn new(event: ServerEvent) -> Self {
+        EventEnvelope {
+            version: EVENT_VERSION,
+            event,
+        }
+    }
+}
+
+/// Results of requests, as replied over WS and as the body of HTTP responses.
+/// Clients of v0 get them in the HTTP response's headers instead.
+#[derive(Serialize, Deserialize)]
+pub enum ReplyData {
+    Ok,
+    SessionToken(SessionToken),
+    RoomUuid(Option<RoomUuid>),
+    Success(bool),
+    /// Negotiated protocol version and capabilities
+    Hello(u32, Vec<String>),
+    Rooms(Vec<RoomInfo>),
+    Members(Vec<MemberInfo>),
+    /// The room and whether it was just created
+    Room(RoomUuid, bool),
+    /// The new user and their session
+    Registered(ClientUuid, SessionToken),
+    Error(ApiError),
+}
+
+/// Machine-readable reason of a failed request
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    NotFound,
+    Unauthorized,
+    Forbidden,
+    /// The room can only be joined with its password
+    PasswordRequired,
+    AlreadyExists,
+    Invalid,
+    /// The server is too busy, the request can be retried later
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::PasswordRequired => StatusCode::UNAUTHORIZED,
+            ErrorCode::AlreadyExists => StatusCode::CONFLICT,
+            ErrorCode::Invalid => StatusCode::BAD_REQUEST,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Best guess for responses without an error body, e.g. from proxies
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::CONFLICT => ErrorCode::AlreadyExists,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            status if status.is_client_error() => ErrorCode::Invalid,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
 }
 
+impl std::error::Error for ApiError {}
+
+/// Request sent as a WS frame. The reply carries the same id.
 #[derive(Serialize, Deserialize)]
+pub struct WsRequest {
+    pub id: u64,
+    pub data: ReqData,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WsReply {
+    pub id: u64,
+    pub data: ReplyData,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub author: String,
     pub contents: String,
@@ -74,21 +331,215 @@ impl ChatMessage {
     }
 }
 
+/// Where a page of a room's history ends: messages sent before `timestamp`, and those sent at
+/// `timestamp` except the latest `skip` of them, which were already seen.
+/// Several messages can share a timestamp, so it alone can't tell where the last page stopped.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HistoryCursor {
+    pub timestamp: DateTime<Utc>,
+    pub skip: usize,
+}
+
+impl HistoryCursor {
+    /// Cursor for the messages sent strictly before the time
+    pub fn before(timestamp: DateTime<Utc>) -> Self {
+        HistoryCursor {
+            timestamp,
+            skip: usize::MAX,
+        }
+    }
+
+    /// Moves the cursor back to a message just seen, if it's the oldest one so far
+    pub fn see(cursor: Option<Self>, timestamp: DateTime<Utc>) -> Self {
+        match cursor {
+            Some(cursor) if cursor.timestamp < timestamp => cursor,
+            Some(cursor) if cursor.timestamp == timestamp => HistoryCursor {
+                timestamp,
+                skip: cursor.skip.saturating_add(1),
+            },
+            _ => HistoryCursor { timestamp, skip: 1 },
+        }
+    }
+
+    /// Tells whether the message at the given time can be on the page, leaving `skip` aside
+    pub fn covers(&self, timestamp: DateTime<Utc>) -> bool {
+        timestamp <= self.timestamp
+    }
+}
+
+/// How much of a room's log is kept. `None` means no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_age_days: Option<u32>,
+    pub max_messages: Option<usize>,
+}
+
+/// What can be seen of a room without joining it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoomInfo {
+    pub uuid: RoomUuid,
+    pub name: RoomName,
+    pub description: String,
+    pub member_count: usize,
+    pub last_activity: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub has_password: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Presence {
+    Online,
+    /// Connected, but its heartbeats are late
+    Idle,
+    Offline,
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Idle => write!(f, "idle"),
+            Presence::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// A connected client, as the admin API shows it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClientSummary {
+    pub uuid: ClientUuid,
+    pub name: ClientName,
+    pub presence: Presence,
+    pub protocol_version: u32,
+    pub rooms: Vec<RoomName>,
+}
+
+/// What a member can do in a room, each role being able to moderate those below it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Role {
+    #[default]
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Member => write!(f, "member"),
+            Role::Moderator => write!(f, "moderator"),
+            Role::Owner => write!(f, "owner"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MemberInfo {
+    pub uuid: ClientUuid,
+    pub name: ClientName,
+    pub presence: Presence,
+    #[serde(default)]
+    pub role: Role,
+}
+
 pub struct Room {
     pub name: RoomName,
     pub uuid: RoomUuid,
     pub members: HashSet<ClientUuid>,
+    /// The room's own retention policy, the server's default (see `Config`) applying without one
+    pub retention: Option<RetentionPolicy>,
+    pub description: String,
+    /// Whoever created the room, the only one who can rename or delete it.
+    /// Rooms created before rooms had owners, or by clients of v0, have none until someone joins.
+    pub owner: Option<ClientUuid>,
+    /// Private rooms are only listed to, and can only be joined by, their owner, members and invitees
+    pub private: bool,
+    /// Hash of the password needed to join, which the owner, members and invitees don't need
+    pub password_hash: Option<String>,
+    pub invited: HashSet<ClientUuid>,
+    pub moderators: HashSet<ClientUuid>,
+    /// Banned users, with when their ban ends
+    pub bans: HashMap<ClientUuid, DateTime<Utc>>,
+    /// Muted users, with when they can send messages again
+    pub mutes: HashMap<ClientUuid, DateTime<Utc>>,
+    /// When the room was created, joined or written to last
+    pub last_activity: Option<DateTime<Utc>>,
 }
 
 impl Room {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, owner: Option<ClientUuid>) -> Self {
         Room {
             name: RoomName(name.to_string()),
             uuid: RoomUuid(Uuid::new_v4()),
             members: HashSet::new(),
+            retention: None,
+            description: String::new(),
+            owner,
+            private: false,
+            password_hash: None,
+            invited: HashSet::new(),
+            moderators: HashSet::new(),
+            bans: HashMap::new(),
+            mutes: HashMap::new(),
+            last_activity: Some(Utc::now()),
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            uuid: self.uuid,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            member_count: self.members.len(),
+            last_activity: self.last_activity,
+            private: self.private,
+            has_password: self.password_hash.is_some(),
+        }
+    }
+
+    /// Tells whether the client can get into the room without any password or invitation
+    pub fn is_admitted(&self, client_uuid: &ClientUuid) -> bool {
+        self.owner.as_ref() == Some(client_uuid)
+            || self.invited.contains(client_uuid)
+            || self.members.contains(client_uuid)
+    }
+
+    pub fn role(&self, client_uuid: &ClientUuid) -> Role {
+        if self.owner.as_ref() == Some(client_uuid) {
+            Role::Owner
+        } else if self.moderators.contains(client_uuid) {
+            Role::Moderator
+        } else {
+            Role::Member
         }
     }
 
+    /// When the client's ban ends, if they are banned
+    pub fn banned_until(&self, client_uuid: &ClientUuid) -> Option<DateTime<Utc>> {
+        self.bans
+            .get(client_uuid)
+            .copied()
+            .filter(|until| *until > Utc::now())
+    }
+
+    /// When the client can send messages again, if they are muted
+    pub fn muted_until(&self, client_uuid: &ClientUuid) -> Option<DateTime<Utc>> {
+        self.mutes
+            .get(client_uuid)
+            .copied()
+            .filter(|until| *until > Utc::now())
+    }
+
+    pub fn is_visible_to(&self, client_uuid: &ClientUuid) -> bool {
+        !self.private || self.is_admitted(client_uuid)
+    }
+
+    pub fn touch(&mut self) {
+        self.last_activity = Some(Utc::now());
+    }
+
     pub fn add(&mut self, client_uuid: ClientUuid) {
         self.members.insert(client_uuid);
     }
@@ -102,38 +553,165 @@ impl Room {
     }
 }
 
+pub struct User {
+    pub name: ClientName,
+    pub password_hash: String,
+}
+
+impl User {
+    pub fn new(name: &str, password_hash: String) -> Self {
+        User {
+            name: ClientName(name.to_string()),
+            password_hash,
+        }
+    }
+}
+
 pub struct Client {
     pub is_alive: bool,
+    pub last_heartbeat: Instant,
     pub name: ClientName,
     pub sender: WSSender,
+    /// Version negotiated on the WS connection, which events are encoded for
+    pub protocol_version: u32,
 }
 
 impl Client {
-    pub fn new(sender: WSSender, name: &str) -> Self {
+    pub fn new(sender: WSSender, name: &str, protocol_version: u32) -> Self {
         Client {
             is_alive: true,
+            last_heartbeat: Instant::now(),
             name: ClientName(name.to_string()),
             sender,
+            protocol_version,
+        }
+    }
+
+    /// Clients are idle once they have missed a heartbeat, before being dropped as dead
+    pub fn presence(&self, heartbeat_interval: Duration) -> Presence {
+        if self.last_heartbeat.elapsed() > heartbeat_interval * 2 {
+            Presence::Idle
+        } else {
+            Presence::Online
+        }
+    }
+
+    pub fn send_event(
+        &self,
+        event: &ServerEvent,
+    ) -> Result<(), SendError<Result<warp::ws::Message, warp::Error>>> {
+        match protocol::encode_event(event, self.protocol_version) {
+            Some(frame) => self.sender.send(Ok(warp::ws::Message::text(frame))),
+            None => Ok(()),
         }
     }
 }
 
-pub enum Protocol {
-    HTTP,
-    WS,
+/// Fresh directory for a test's files. It's part of the library so that the binaries' tests
+/// can use it as well.
+#[doc(hidden)]
+pub fn test_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("chatter-test-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
-pub fn get_addr_str(prot: Protocol) -> String {
-    let args: Vec<String> = std::env::args().collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERROR_CODES: [ErrorCode; 8] = [
+        ErrorCode::NotFound,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::PasswordRequired,
+        ErrorCode::AlreadyExists,
+        ErrorCode::Invalid,
+        ErrorCode::Unavailable,
+        ErrorCode::Internal,
+    ];
+
+    #[test]
+    fn error_codes_are_found_back_from_their_status() {
+        for code in ERROR_CODES {
+            let expected = match code {
+                // Shares its status with Unauthorized, which it is a case of
+                ErrorCode::PasswordRequired => ErrorCode::Unauthorized,
+                code => code,
+            };
+            assert_eq!(ErrorCode::from_status(code.status()), expected);
+        }
+    }
+
+    #[test]
+    fn error_codes_are_errors() {
+        for code in ERROR_CODES {
+            let status = code.status();
+            assert!(status.is_client_error() || status.is_server_error());
+        }
+    }
+
+    #[test]
+    fn other_statuses_are_guessed_by_their_class() {
+        assert_eq!(
+            ErrorCode::from_status(StatusCode::UNPROCESSABLE_ENTITY),
+            ErrorCode::Invalid
+        );
+        assert_eq!(
+            ErrorCode::from_status(StatusCode::TOO_MANY_REQUESTS),
+            ErrorCode::Invalid
+        );
+        assert_eq!(
+            ErrorCode::from_status(StatusCode::BAD_GATEWAY),
+            ErrorCode::Internal
+        );
+    }
+
+    #[test]
+    fn errors_are_sent_with_their_code_by_name() {
+        let error = ApiError::new(ErrorCode::AlreadyExists, "taken");
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(json, r#"{"code":"AlreadyExists","message":"taken"}"#);
+        let error: ApiError = serde_json::from_str(&json).unwrap();
+        assert_eq!(error.code, ErrorCode::AlreadyExists);
+    }
+
+    fn name(name: &str) -> ClientName {
+        ClientName(name.to_string())
+    }
+
+    #[test]
+    fn user_names_differing_by_case_are_the_same() {
+        assert_eq!(name("Alice").normalized(), name("alice").normalized());
+        assert_ne!(name("alice").normalized(), name("alice2").normalized());
+    }
+
+    #[test]
+    fn room_names_differing_by_case_or_surrounding_spaces_are_the_same() {
+        let room = |name: &str| RoomName(name.to_string()).normalized();
+        assert_eq!(room(" General "), room("general"));
+        assert_ne!(room("general chat"), room("generalchat"));
+    }
 
-    let addr = if args.len() == 1 {
-        LOCALHOST.to_string()
-    } else {
-        args[1].clone()
-    };
+    #[test]
+    fn valid_names_are_accepted() {
+        for valid in ["alice", "Bob_2", "c.d-e", &"x".repeat(MAX_NAME_LEN)] {
+            assert_eq!(name(valid).validate(), Ok(()), "{}", valid);
+        }
+    }
 
-    match prot {
-        Protocol::HTTP => addr + PORT_HTTP,
-        Protocol::WS => addr + PORT_WS,
+    #[test]
+    fn invalid_names_are_refused() {
+        let too_long = "x".repeat(MAX_NAME_LEN + 1);
+        for invalid in ["", &too_long, "al ice", "zoë", "a/b", "<b>"] {
+            assert!(name(invalid).validate().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_refused_whatever_their_case() {
+        for reserved in [SERVER_SIGNATURE, "server", "YOU", "You"] {
+            assert!(name(reserved).validate().is_err(), "{}", reserved);
+        }
     }
 }
@@ -0,0 +1,241 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::{
+    ChatMessage, ClientName, ClientUuid, EventEnvelope, ReqData, RoomName, RoomUuid, ServerEvent,
+    EVENT_VERSION, SERVER_SIGNATURE,
+};
+
+/// Version of the request format. v0 is the format from before sessions (see `LegacyReqData`),
+/// whose requests carry no version, and whose events were bare `ChatMessage`s.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Requests can be sent over WS with ids (see `WsRequest`)
+pub const CAP_FULL_DUPLEX: &str = "full_duplex";
+/// Pushed messages are typed `ServerEvent`s rather than bare chat messages
+pub const CAP_EVENTS: &str = "events";
+
+pub fn capabilities() -> Vec<String> {
+    vec![CAP_FULL_DUPLEX.to_string(), CAP_EVENTS.to_string()]
+}
+
+/// Agrees on the highest version both sides speak and on the capabilities both have.
+pub fn negotiate(version: u32, capabilities: &[String]) -> (u32, Vec<String>) {
+    let common = self::capabilities()
+        .into_iter()
+        .filter(|capability| capabilities.contains(capability))
+        .collect();
+    (version.min(PROTOCOL_VERSION), common)
+}
+
+/// Requests of v0, when clients were known by their uuid, which `LoginData` gave out.
+/// Their replies are in the `client_uuid`, `room_uuid` and `success` headers, or in the status.
+#[derive(Serialize, Deserialize)]
+pub enum LegacyReqData {
+    HeartbeatData(ClientUuid),
+    CreateRoomData(RoomName),
+    GetRoomData(RoomName),
+    JoinRoomData(ClientName, ClientUuid, RoomUuid),
+    SendMsgData(ChatMessage, RoomUuid),
+    LoginData(ClientName),
+    RegistrationData(ClientName),
+    LeaveRoomData(RoomUuid, ClientUuid),
+    ExitAppData(ClientUuid),
+}
+
+/// A request in any of the formats the server understands
+pub enum Request {
+    Legacy(LegacyReqData),
+    /// A request of the current format without a version, from the clients of before the
+    /// negotiation. They expect replies in headers as in v0, but events in envelopes.
+    Unversioned(ReqData),
+    Versioned(VersionedReq),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VersionedReq {
+    pub version: u32,
+    pub data: ReqData,
+}
+
+impl VersionedReq {
+    pub fn new(data: ReqData) -> Self {
+        VersionedReq {
+            version: PROTOCOL_VERSION,
+            data,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    UnsupportedVersion(u64),
+    Invalid(serde_json::Error),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            DecodeError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        DecodeError::Invalid(e)
+    }
+}
+
+/// Decodes a request of any supported version. Requests without one are of v0 if they have its
+/// shape, and of the current format otherwise: the two never share one.
+pub fn decode_request(json: &[u8]) -> Result<Request, DecodeError> {
+    let value: Value = serde_json::from_slice(json)?;
+    match value.get("version").and_then(Value::as_u64) {
+        None => match LegacyReqData::deserialize(&value) {
+            Ok(data) => Ok(Request::Legacy(data)),
+            Err(_) => Ok(Request::Unversioned(serde_json::from_value(value)?)),
+        },
+        Some(version) if version > PROTOCOL_VERSION as u64 => {
+            Err(DecodeError::UnsupportedVersion(version))
+        }
+        Some(_) => Ok(Request::Versioned(serde_json::from_value(value)?)),
+    }
+}
+
+/// Decodes an event of any supported version. Events of v0 carry no room.
+pub fn decode_event(json: &str) -> Result<ServerEvent, DecodeError> {
+    let value: Value = serde_json::from_str(json)?;
+    match value.get("version").and_then(Value::as_u64) {
+        None => {
+            let msg: ChatMessage = serde_json::from_value(value)?;
+            Ok(ServerEvent::Message(RoomUuid(Uuid::nil()), msg))
+        }
+        Some(version) => match serde_json::from_value::<EventEnvelope>(value) {
+            Ok(envelope) => Ok(envelope.event),
+            Err(_) if version > EVENT_VERSION as u64 => {
+                Err(DecodeError::UnsupportedVersion(version))
+            }
+            Err(e) => Err(e.into()),
+        },
+    }
+}
+
+/// Encodes the event for a client speaking the given version.
+/// Clients of v0 only understand chat messages, so other events are turned into server messages,
+/// or dropped if they are of no interest.
+pub fn encode_event(event: &ServerEvent, version: u32) -> Option<String> {
+    if version > LEGACY_PROTOCOL_VERSION {
+        return Some(serde_json::to_string(&EventEnvelope::new(event.clone())).unwrap());
+    }
+    let contents = match event {
+        ServerEvent::Message(_, msg) => return Some(serde_json::to_string(msg).unwrap()),
+        ServerEvent::DirectMessage(msg) => {
+            let mut msg = msg.clone();
+            msg.author = format!("{} (private)", msg.author);
+            return Some(serde_json::to_string(&msg).unwrap());
+        }
+        ServerEvent::Announcement(msg) => format!("Announcement: {}", msg.contents),
+        ServerEvent::UserJoined(_, name, _) => format!("{} has joined the chat", name.0),
+        ServerEvent::UserLeft(_, name, _) => format!("{} has left the chat", name.0),
+        ServerEvent::RoomDeleted(_, name) => format!("Room '{}' was deleted", name.0),
+        ServerEvent::RoomRenamed(_, name) => format!("The room was renamed to '{}'", name.0),
+        ServerEvent::Invited(room_name, by) => {
+            format!("{} invited you to room '{}'", by.0, room_name.0)
+        }
+        ServerEvent::Muted(_, Some(until)) => format!("You are muted until {}", until),
+        ServerEvent::Muted(_, None) => "You are no longer muted".to_string(),
+        ServerEvent::Error(error) => format!("Error: {}", error),
+        ServerEvent::Kicked(_, reason) => format!("You were removed from the room: {}", reason),
+    };
+    let msg = ChatMessage::new(SERVER_SIGNATURE, &contents);
+    Some(serde_json::to_string(&msg).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(json: &str) -> Request {
+        decode_request(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn requests_of_v0_are_decoded_as_such() {
+        let client_uuid = Uuid::new_v4();
+        assert!(matches!(
+            decode(r#"{"LoginData":"alice"}"#),
+            Request::Legacy(LegacyReqData::LoginData(ClientName(name))) if name == "alice"
+        ));
+        assert!(matches!(
+            decode(&format!(r#"{{"HeartbeatData":"{}"}}"#, client_uuid)),
+            Request::Legacy(LegacyReqData::HeartbeatData(ClientUuid(uuid))) if uuid == client_uuid
+        ));
+        assert!(matches!(
+            decode(&format!(
+                r#"{{"JoinRoomData":["alice","{}","{}"]}}"#,
+                client_uuid,
+                Uuid::new_v4()
+            )),
+            Request::Legacy(LegacyReqData::JoinRoomData(..))
+        ));
+    }
+
+    #[test]
+    fn unversioned_requests_of_the_current_format_are_not_taken_for_v0() {
+        assert!(matches!(
+            decode(r#"{"LoginData":["alice","secret"]}"#),
+            Request::Unversioned(ReqData::LoginData(..))
+        ));
+        // Session tokens are never uuids
+        let token = "a".repeat(48);
+        assert!(matches!(
+            decode(&format!(r#"{{"HeartbeatData":"{}"}}"#, token)),
+            Request::Unversioned(ReqData::HeartbeatData(..))
+        ));
+    }
+
+    #[test]
+    fn versioned_requests_are_decoded_up_to_the_current_version() {
+        let json = format!(
+            r#"{{"version":{},"data":{{"LoginData":["alice","secret"]}}}}"#,
+            PROTOCOL_VERSION
+        );
+        assert!(matches!(
+            decode(&json),
+            Request::Versioned(VersionedReq {
+                version: PROTOCOL_VERSION,
+                data: ReqData::LoginData(..)
+            })
+        ));
+        let json = format!(
+            r#"{{"version":{},"data":{{"LoginData":["alice","secret"]}}}}"#,
+            PROTOCOL_VERSION + 1
+        );
+        assert!(matches!(
+            decode_request(json.as_bytes()),
+            Err(DecodeError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn events_are_bare_chat_messages_for_v0_only() {
+        let msg = ChatMessage::new("alice", "hi");
+        let event = ServerEvent::Message(RoomUuid(Uuid::new_v4()), msg);
+        let legacy = encode_event(&event, LEGACY_PROTOCOL_VERSION).unwrap();
+        let legacy: ChatMessage = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(legacy.contents, "hi");
+        let current = encode_event(&event, PROTOCOL_VERSION).unwrap();
+        let envelope: EventEnvelope = serde_json::from_str(&current).unwrap();
+        assert!(matches!(envelope.event, ServerEvent::Message(..)));
+    }
+}
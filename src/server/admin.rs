@@ -0,0 +1,236 @@
+//! Admin routes, for operators of the server and the `chatter-admin` binary.
+//!
+//! They are authenticated with `Authorization: Bearer <admin_token>`, the token being set in the
+//! server's config; without one the admin API is off. Like the REST routes, they take and reply
+//! plain JSON, and errors are replied as an `ApiError`.
+
+use std::time::Duration;
+
+use chatter::common::config::Config;
+use chatter::common::{
+    ApiError, ChatMessage, ClientName, ClientSummary, ClientUuid, ErrorCode, Password, Room,
+    RoomInfo, RoomUuid, ServerEvent, SERVER_SIGNATURE,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::hash_password_async;
+use crate::handler::no_such_room;
+use crate::rest::{bearer_token, no_content, respond, uuid_param};
+use crate::{Context, Response};
+
+#[derive(Deserialize)]
+struct NewAnnouncement {
+    contents: String,
+}
+
+#[derive(Deserialize)]
+struct NewPassword {
+    name: ClientName,
+    password: Password,
+}
+
+#[derive(Serialize)]
+struct Reloaded {
+    /// Changed settings which only take effect once the server restarts
+    restart_required: Vec<&'static str>,
+}
+
+/// Compares in constant time, so that the token can't be guessed from how long checking it takes
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+fn authorize(ctx: &Context) -> Result<(), ApiError> {
+    let token = bearer_token(ctx)?;
+    let app = ctx.app_state.lock().unwrap();
+    match &app.config.admin_token {
+        None => Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "The admin API is disabled",
+        )),
+        Some(admin_token) if tokens_match(admin_token, &token.0) => Ok(()),
+        Some(_) => Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Invalid admin token",
+        )),
+    }
+}
+
+fn list_clients(ctx: &Context) -> Result<Vec<ClientSummary>, ApiError> {
+    authorize(ctx)?;
+    let app = ctx.app_state.lock().unwrap();
+    let heartbeat_interval = Duration::from_millis(app.config.heartbeat_interval_ms);
+    let mut clients = app
+        .clients
+        .iter()
+        .map(|(client_uuid, client)| {
+            let mut rooms = app
+                .rooms
+                .values()
+                .filter(|room| room.contains(client_uuid))
+                .map(|room| room.name.clone())
+                .collect::<Vec<_>>();
+            rooms.sort_by(|a, b| a.0.cmp(&b.0));
+            ClientSummary {
+                uuid: *client_uuid,
+                name: client.name.clone(),
+                presence: client.presence(heartbeat_interval),
+                protocol_version: client.protocol_version,
+                rooms,
+            }
+        })
+        .collect::<Vec<_>>();
+    clients.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+    Ok(clients)
+}
+
+pub async fn handle_list_clients(ctx: Context) -> Response {
+    respond(StatusCode::OK, list_clients(&ctx))
+}
+
+/// Disconnects the client from every room and closes its connection
+fn disconnect_client(ctx: &Context) -> Result<(), ApiError> {
+    authorize(ctx)?;
+    let client_uuid = ClientUuid(uuid_param(ctx, "id")?);
+    let mut app = ctx.app_state.lock().unwrap();
+    if !app.clients.contains_key(&client_uuid) {
+        return Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("No client {} is connected", client_uuid.0),
+        ));
+    }
+    println!("Disconnecting client {} on admin request", client_uuid.0);
+    app.force_disconnect(client_uuid);
+    Ok(())
+}
+
+pub async fn handle_disconnect_client(ctx: Context) -> Response {
+    no_content(disconnect_client(&ctx))
+}
+
+/// Lists every room, private ones included
+fn list_rooms(ctx: &Context) -> Result<Vec<RoomInfo>, ApiError> {
+    authorize(ctx)?;
+    let app = ctx.app_state.lock().unwrap();
+    let mut rooms = app.rooms.values().map(Room::info).collect::<Vec<_>>();
+    rooms.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+    Ok(rooms)
+}
+
+pub async fn handle_list_rooms(ctx: Context) -> Response {
+    respond(StatusCode::OK, list_rooms(&ctx))
+}
+
+fn delete_room(ctx: &Context) -> Result<(), ApiError> {
+    authorize(ctx)?;
+    let room_uuid = RoomUuid(uuid_param(ctx, "id")?);
+    let mut app = ctx.app_state.lock().unwrap();
+    if !app.rooms.contains_key(&room_uuid) {
+        return Err(no_such_room(room_uuid));
+    }
+    println!("Deleting room {} on admin request", room_uuid.0);
+    app.delete_room(room_uuid);
+    Ok(())
+}
+
+pub async fn handle_delete_room(ctx: Context) -> Response {
+    no_content(delete_room(&ctx))
+}
+
+/// Sends the announcement to every connected client, and records it in every room's log
+async fn announce(ctx: &mut Context) -> Result<(), ApiError> {
+    authorize(ctx)?;
+    let announcement: NewAnnouncement = ctx
+        .body_json()
+        .await
+        .map_err(|e| ApiError::new(ErrorCode::Invalid, format!("Invalid announcement: {}", e)))?;
+    if announcement.contents.trim().is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            "The announcement is empty".to_string(),
+        ));
+    }
+    let app = ctx.app_state.lock().unwrap();
+    let msg = ChatMessage::new(SERVER_SIGNATURE, &announcement.contents);
+    let logged = ChatMessage {
+        contents: format!("Announcement: {}", msg.contents),
+        ..msg.clone()
+    };
+    for room_uuid in app.rooms.keys() {
+        app.log(&logged, *room_uuid);
+    }
+    app.broadcast(ServerEvent::Announcement(msg));
+    println!("Announced '{}' on admin request", announcement.contents);
+    Ok(())
+}
+
+pub async fn handle_announce(mut ctx: Context) -> Response {
+    no_content(announce(&mut ctx).await)
+}
+
+/// Reads the config again, from the same sources as at startup
+fn reload_config(ctx: &Context) -> Result<Reloaded, ApiError> {
+    authorize(ctx)?;
+    let (config, _) = Config::load().map_err(|e| {
+        ApiError::new(
+            ErrorCode::Invalid,
+            format!("Invalid configuration: {:#}", e),
+        )
+    })?;
+    let restart_required = ctx.app_state.lock().unwrap().reload_config(config);
+    println!("Reloaded the config on admin request");
+    Ok(Reloaded { restart_required })
+}
+
+pub async fn handle_reload_config(ctx: Context) -> Response {
+    respond(StatusCode::OK, reload_config(&ctx))
+}
+
+/// Sets the user's password, which is how users without one (from before passwords, or registered
+/// by v0 clients) get one, and how forgotten ones are reset. Their sessions are ended.
+async fn set_password(ctx: &mut Context) -> Result<(), ApiError> {
+    authorize(ctx)?;
+    let new_password: NewPassword = ctx
+        .body_json()
+        .await
+        .map_err(|e| ApiError::new(ErrorCode::Invalid, format!("Invalid password: {}", e)))?;
+    if new_password.password.0.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            "Passwords cannot be empty",
+        ));
+    }
+    let no_such_user = || {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("No user named '{}'", new_password.name.0),
+        )
+    };
+    let client_uuid = ctx
+        .app_state
+        .lock()
+        .unwrap()
+        .find_user_by_name(&new_password.name)
+        .ok_or_else(no_such_user)?;
+    // Hashing is slow on purpose, so it's done without holding the lock
+    let password_hash = hash_password_async(new_password.password)
+        .await
+        .ok_or_else(|| ApiError::new(ErrorCode::Internal, "Hashing the password failed"))?;
+    let mut app = ctx.app_state.lock().unwrap();
+    let user = app.users.get_mut(&client_uuid).ok_or_else(no_such_user)?;
+    user.password_hash = password_hash;
+    println!("Set the password of {} on admin request", user.name.0);
+    app.persist_user(client_uuid);
+    app.sessions
+        .retain(|_, session| session.client_uuid != client_uuid);
+    Ok(())
+}
+
+pub async fn handle_set_password(mut ctx: Context) -> Response {
+    no_content(set_password(&mut ctx).await)
+}
@@ -0,0 +1,83 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chatter::common::{ClientUuid, Password, SessionToken};
+use chrono::{DateTime, Duration, Utc};
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::Rng;
+use std::sync::OnceLock;
+
+const SESSION_TTL_HOURS: i64 = 24;
+const SESSION_TOKEN_LEN: usize = 48;
+
+/// Hashes the password with Argon2 and a random salt, in the PHC string format.
+pub fn hash_password(password: &Password) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.0.as_bytes(), &salt)
+        .ok()
+        .map(|hash| hash.to_string())
+}
+
+pub fn verify_password(password: &Password, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed_hash) => Argon2::default()
+            .verify_password(password.0.as_bytes(), &parsed_hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Hashing and verifying are slow on purpose, so the async versions run them off the runtime
+pub async fn hash_password_async(password: Password) -> Option<String> {
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .ok()
+        .flatten()
+}
+
+pub async fn verify_password_async(password: Password, password_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || verify_password(&password, &password_hash))
+        .await
+        .unwrap_or(false)
+}
+
+/// Hash to verify passwords against when there is none, so that it takes as long as usual
+pub fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password(&Password(String::new())).unwrap_or_default())
+}
+
+pub struct Session {
+    pub client_uuid: ClientUuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Session {
+    /// Creates a new session along with its opaque, random token.
+    pub fn new(client_uuid: ClientUuid) -> (SessionToken, Self) {
+        let token = OsRng
+            .sample_iter(&Alphanumeric)
+            .take(SESSION_TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        let session = Session {
+            client_uuid,
+            expires_at: Self::next_expiry(),
+        };
+        (SessionToken(token), session)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+
+    /// Sessions expire after a period of inactivity, so every use extends them.
+    pub fn refresh(&mut self) {
+        self.expires_at = Self::next_expiry();
+    }
+
+    fn next_expiry() -> DateTime<Utc> {
+        Utc::now() + Duration::hours(SESSION_TTL_HOURS)
+    }
+}
@@ -1,25 +1,26 @@
 use std::collections::hash_map::Entry;
-use std::fmt::Display;
+use std::future::{self, Future};
+use std::time::{Duration, Instant};
 
+use chatter::common::protocol::{self, Request, VersionedReq};
 use chatter::common::{
-    ChatMessage, ReqData, Room, RoomUuid, CLIENT_UUID_HEADER, ROOM_UUID_HEADER, SUCCESS_HEADER,
+    ApiError, ChatMessage, ClientName, ClientUuid, ErrorCode, HistoryCursor, MemberInfo, Password,
+    Presence, ReplyData, ReqData, RetentionPolicy, Role, Room, RoomInfo, RoomName, RoomUuid,
+    ServerEvent, SessionToken, MAX_DESCRIPTION_LEN, ROOM_UUID_HEADER, SERVER_SIGNATURE,
+    SESSION_TOKEN_HEADER, SUCCESS_HEADER,
 };
+use chrono::Utc;
+use hyper::header::CONTENT_TYPE;
 use hyper::StatusCode;
-use uuid::Uuid;
+use tower_service::Service;
 use warp::Reply;
 
-use crate::logging::log_msg;
+use crate::auth::{dummy_hash, hash_password_async, verify_password_async};
+use crate::logging::{read_history, ConversationId, LogEntry, LogError};
 use crate::AppState;
 use crate::Arc;
 use crate::Mutex;
-use crate::{ws, Context, Response, ResultWS, SERVER_SIGNATURE};
-
-fn bad_json_resp(err: impl Display) -> Response {
-    hyper::Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .body(format!("could not parse JSON: {}", err).into())
-        .unwrap()
-}
+use crate::{legacy, ws, Context, Response, ResultWS};
 
 pub fn response_with_code(code: StatusCode) -> Response {
     let reason = code
@@ -46,193 +47,1342 @@ pub async fn handle_registration(
     Ok(ws.on_upgrade(move |socket| ws::new_client_connection(socket, app)))
 }
 
-fn response_with_header<T>(value: &T, header: &str) -> Response
+/// Hands the upgrade request over to the WS server's filter, so that both transports are the same.
+pub async fn handle_ws_upgrade(ctx: Context) -> Response {
+    let mut service = warp::service(ws::ws_route(ctx.app_state));
+    match service.call(ctx.req).await {
+        Ok(resp) => resp,
+        Err(never) => match never {},
+    }
+}
+
+pub fn response_with_header<T>(value: &T, header: &str) -> Response
 where
     T: ?Sized + serde::Serialize,
 {
-    match serde_json::to_string(value) {
-        Err(e) => bad_json_resp(e),
-        Ok(value_str) => hyper::Response::builder()
-            .status(StatusCode::OK)
-            .header(header, value_str)
-            .body("200: Ok".into())
-            .unwrap(),
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .header(header, serde_json::to_string(value).unwrap())
+        .body("200: Ok".into())
+        .unwrap()
+}
+
+/// Responds with the reply as JSON, under the status of its error code if it's an error
+fn reply_response(reply: &ReplyData) -> Response {
+    let code = match reply {
+        ReplyData::Error(error) => error.code.status(),
+        _ => StatusCode::OK,
+    };
+    hyper::Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(reply).unwrap().into())
+        .unwrap()
+}
+
+pub fn error_response(error: ApiError) -> Response {
+    reply_response(&ReplyData::Error(error))
+}
+
+/// Responds the way clients of before the negotiation expect, with the result in a header
+fn legacy_response(reply: ReplyData) -> Response {
+    match reply {
+        ReplyData::Ok => response_with_code(StatusCode::OK),
+        // Replies which didn't exist then have no header to go in
+        reply @ (ReplyData::Hello(..)
+        | ReplyData::Rooms(..)
+        | ReplyData::Members(..)
+        | ReplyData::Room(..)
+        | ReplyData::Registered(..)) => reply_response(&reply),
+        ReplyData::SessionToken(token) => response_with_header(&token, SESSION_TOKEN_HEADER),
+        ReplyData::RoomUuid(room_uuid) => response_with_header(&room_uuid, ROOM_UUID_HEADER),
+        ReplyData::Success(success) => response_with_header(&success, SUCCESS_HEADER),
+        ReplyData::Error(error) => response_with_code(error.code.status()),
     }
 }
 
-async fn request<F>(mut ctx: Context, f: F, request_type: &str) -> Response
+/// Request closures return this error when given the wrong kind of request
+fn wrong_request(request_type: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::Invalid,
+        format!("Invalid {} request received", request_type),
+    )
+}
+
+/// Replies to the request with the result of `f`, in the format of the request's version
+async fn request<F>(ctx: Context, f: F) -> Response
 where
-    F: Fn(ReqData) -> Result<StatusCode, ()>,
+    F: Fn(ReqData) -> Result<ReplyData, ApiError>,
 {
-    match ctx.body_json::<ReqData>().await {
-        Err(e) => bad_json_resp(e),
-        Ok(v) => match f(v) {
-            Ok(code) => response_with_code(code),
-            Err(_) => bad_json_resp(format!("Invalid {} request received", request_type)),
-        },
-    }
+    request_async(ctx, |data| future::ready(f(data))).await
 }
 
-async fn request_with_header<F, H>(
-    mut ctx: Context,
-    f: F,
-    header: &str,
-    request_type: &str,
-) -> Response
+/// Same as `request`, for requests which have to wait on something, e.g. the logs.
+/// Requests of v0 are handled by `legacy` instead, whatever the endpoint.
+async fn request_async<F, Fut>(mut ctx: Context, f: F) -> Response
 where
-    F: Fn(ReqData) -> Result<H, ()>,
-    H: serde::Serialize,
+    F: FnOnce(ReqData) -> Fut,
+    Fut: Future<Output = Result<ReplyData, ApiError>>,
 {
-    match ctx.body_json::<ReqData>().await {
-        Err(e) => bad_json_resp(e),
-        Ok(v) => match f(v) {
-            Ok(v) => response_with_header(&v, header),
-            Err(_) => bad_json_resp(format!("Invalid {} request received", request_type)),
-        },
+    let app_state = ctx.app_state.clone();
+    match ctx.body_request().await {
+        Err(e) => error_response(ApiError::new(
+            ErrorCode::Invalid,
+            format!("could not parse JSON: {}", e),
+        )),
+        Ok(Request::Legacy(data)) => legacy::handle_request(&app_state, data).await,
+        Ok(Request::Unversioned(data)) => {
+            legacy_response(f(data).await.unwrap_or_else(ReplyData::Error))
+        }
+        Ok(Request::Versioned(VersionedReq { data, .. })) => {
+            reply_response(&f(data).await.unwrap_or_else(ReplyData::Error))
+        }
+    }
+}
+
+/// Negotiates over HTTP, which servers from before the negotiation answer right away, unlike WS.
+/// The WS connection still has to be told the version its events are encoded for.
+pub async fn handle_hello(ctx: Context) -> Response {
+    let f = |req_data| match req_data {
+        ReqData::HelloData(version, capabilities) => {
+            let (version, capabilities) = protocol::negotiate(version, &capabilities);
+            Ok(ReplyData::Hello(version, capabilities))
+        }
+        _ => Err(wrong_request("hello")),
+    };
+    request(ctx, f).await
+}
+
+pub fn authenticate(app: &mut AppState, token: &SessionToken) -> Result<ClientUuid, ApiError> {
+    app.authenticate(token)
+        .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Invalid or expired session"))
+}
+
+pub fn no_such_room(room_uuid: RoomUuid) -> ApiError {
+    ApiError::new(ErrorCode::NotFound, format!("No room {}", room_uuid.0))
+}
+
+pub fn not_a_member(room_uuid: RoomUuid) -> ApiError {
+    ApiError::new(
+        ErrorCode::Forbidden,
+        format!("Not a member of room {}", room_uuid.0),
+    )
+}
+
+pub fn check_member(
+    app: &AppState,
+    client_uuid: ClientUuid,
+    room_uuid: RoomUuid,
+) -> Result<(), ApiError> {
+    match app.rooms.get(&room_uuid) {
+        None => Err(no_such_room(room_uuid)),
+        Some(room) if !room.contains(&client_uuid) => Err(not_a_member(room_uuid)),
+        Some(_) => Ok(()),
+    }
+}
+
+pub fn check_moderator(
+    app: &AppState,
+    client_uuid: ClientUuid,
+    room_uuid: RoomUuid,
+) -> Result<(), ApiError> {
+    match app.rooms.get(&room_uuid) {
+        None => Err(no_such_room(room_uuid)),
+        Some(room) if room.role(&client_uuid) == Role::Member => Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Only the room's owner and moderators can do that",
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+pub fn check_owner(
+    app: &AppState,
+    client_uuid: ClientUuid,
+    room_uuid: RoomUuid,
+) -> Result<(), ApiError> {
+    match app.rooms.get(&room_uuid) {
+        None => Err(no_such_room(room_uuid)),
+        Some(room) if room.owner != Some(client_uuid) => Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Only the room's owner can do that",
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Makes sure the room's log is up to date before reading it
+pub async fn flush_room_log(app_state: &Arc<Mutex<AppState>>, room_uuid: RoomUuid) {
+    let logger = app_state.lock().unwrap().logger.clone();
+    logger.flush(room_uuid).await;
+}
+
+/// Handles a request sent over WS, the same way as its HTTP endpoint
+pub async fn handle_ws_request(app_state: &Arc<Mutex<AppState>>, req_data: ReqData) -> ReplyData {
+    let result = match req_data {
+        ReqData::HeartbeatData(token) => heartbeat(app_state, &token).map(|_| ReplyData::Ok),
+        ReqData::CreateRoomData(token, room_name) => {
+            create_room(app_state, &token, &room_name).map(|uuid| ReplyData::RoomUuid(Some(uuid)))
+        }
+        ReqData::GetRoomData(token, room_name) => {
+            get_room(app_state, &token, &room_name).map(ReplyData::RoomUuid)
+        }
+        ReqData::JoinRoomData(token, room_uuid) => join_room(app_state, &token, room_uuid, None)
+            .await
+            .map(ReplyData::Success),
+        ReqData::JoinProtectedRoomData(token, room_uuid, password) => {
+            join_room(app_state, &token, room_uuid, Some(password))
+                .await
+                .map(ReplyData::Success)
+        }
+        ReqData::SendMsgData(token, msg, room_uuid) => {
+            send_msg(app_state, &token, msg, room_uuid).map(|_| ReplyData::Ok)
+        }
+        ReqData::LoginData(client_name, password) => login(app_state, &client_name, password)
+            .await
+            .map(ReplyData::SessionToken),
+        ReqData::LeaveRoomData(token, room_uuid) => {
+            leave_room(app_state, &token, room_uuid).map(|_| ReplyData::Ok)
+        }
+        ReqData::ExitAppData(token) => exit_app(app_state, &token).map(|_| ReplyData::Ok),
+        ReqData::LoadHistoryData(token, room_uuid, before) => {
+            let before = HistoryCursor::before(before);
+            load_history(app_state, &token, room_uuid, before)
+                .await
+                .map(|_| ReplyData::Ok)
+        }
+        ReqData::LoadHistoryPageData(token, room_uuid, before) => {
+            load_history(app_state, &token, room_uuid, before)
+                .await
+                .map(|_| ReplyData::Ok)
+        }
+        ReqData::ListRoomsData(token) => list_rooms(app_state, &token).map(ReplyData::Rooms),
+        ReqData::DescribeRoomData(token, room_uuid, description) => {
+            describe_room(app_state, &token, room_uuid, description).map(|_| ReplyData::Ok)
+        }
+        ReqData::ListMembersData(token, room_uuid) => {
+            list_members(app_state, &token, room_uuid).map(ReplyData::Members)
+        }
+        ReqData::GetOrCreateRoomData(token, room_name) => {
+            get_or_create_room(app_state, &token, &room_name)
+                .map(|(room_uuid, created)| ReplyData::Room(room_uuid, created))
+        }
+        ReqData::RenameRoomData(token, room_uuid, room_name) => {
+            rename_room(app_state, &token, room_uuid, &room_name).map(|_| ReplyData::Ok)
+        }
+        ReqData::DeleteRoomData(token, room_uuid) => {
+            delete_room(app_state, &token, room_uuid).map(|_| ReplyData::Ok)
+        }
+        ReqData::DirectMsgData(token, recipient, msg) => {
+            direct_msg(app_state, &token, &recipient, msg).map(|_| ReplyData::Ok)
+        }
+        ReqData::SetRoomPrivateData(token, room_uuid, private) => {
+            set_room_private(app_state, &token, room_uuid, private).map(|_| ReplyData::Ok)
+        }
+        ReqData::SetRetentionData(token, room_uuid, retention) => {
+            set_retention(app_state, &token, room_uuid, retention).map(|_| ReplyData::Ok)
+        }
+        ReqData::SetRoomPasswordData(token, room_uuid, password) => {
+            set_room_password(app_state, &token, room_uuid, password)
+                .await
+                .map(|_| ReplyData::Ok)
+        }
+        ReqData::InviteData(token, room_uuid, invitee) => {
+            set_invited(app_state, &token, room_uuid, &invitee, true).map(|_| ReplyData::Ok)
+        }
+        ReqData::UninviteData(token, room_uuid, invitee) => {
+            set_invited(app_state, &token, room_uuid, &invitee, false).map(|_| ReplyData::Ok)
+        }
+        ReqData::KickData(token, room_uuid, target) => {
+            kick(app_state, &token, room_uuid, &target).map(|_| ReplyData::Ok)
+        }
+        ReqData::BanData(token, room_uuid, target, minutes) => {
+            ban(app_state, &token, room_uuid, &target, minutes).map(|_| ReplyData::Ok)
+        }
+        ReqData::MuteData(token, room_uuid, target, minutes) => {
+            mute(app_state, &token, room_uuid, &target, minutes).map(|_| ReplyData::Ok)
+        }
+        ReqData::SetRoleData(token, room_uuid, target, role) => {
+            set_role(app_state, &token, room_uuid, &target, role).map(|_| ReplyData::Ok)
+        }
+        ReqData::RegistrationData(..) | ReqData::ConnectData(..) | ReqData::HelloData(..) => {
+            Err(ApiError::new(ErrorCode::Invalid, "Unexpected request"))
+        }
+    };
+    result.unwrap_or_else(ReplyData::Error)
+}
+
+/// Unknown users and wrong passwords get the same answer, so that logging in doesn't tell which names exist
+fn wrong_credentials() -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, "Wrong name or password")
+}
+
+/// Users without a password, persisted before passwords were introduced or registered by v0
+/// clients, can't log in until an administrator sets one.
+async fn login(
+    app_state: &Arc<Mutex<AppState>>,
+    client_name: &ClientName,
+    password: Password,
+) -> Result<SessionToken, ApiError> {
+    let user = {
+        let app = app_state.lock().unwrap();
+        app.find_user_by_name(client_name).map(|client_uuid| {
+            let password_hash = app.users[&client_uuid].password_hash.clone();
+            (client_uuid, password_hash)
+        })
+    };
+    // Hashing and verifying are done without holding the lock
+    match user {
+        Some((_, password_hash)) if password_hash.is_empty() => {
+            // Takes as long as for a user with a password
+            verify_password_async(password, dummy_hash().to_string()).await;
+            Err(wrong_credentials())
+        }
+        None => {
+            verify_password_async(password, dummy_hash().to_string()).await;
+            Err(wrong_credentials())
+        }
+        Some((client_uuid, password_hash)) => {
+            if verify_password_async(password, password_hash).await {
+                Ok(app_state.lock().unwrap().open_session(client_uuid))
+            } else {
+                Err(wrong_credentials())
+            }
+        }
     }
 }
 
 pub async fn handle_login(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
-    let f = |req_data| match req_data {
-        ReqData::LoginData(client_name) => {
-            let client_uuid = app_state.lock().unwrap().clients.iter().find_map(|(k, v)| {
-                if v.name == client_name {
-                    Some(*k)
-                } else {
-                    None
-                }
-            });
-            Ok(client_uuid)
+    let f = |req_data| async {
+        match req_data {
+            ReqData::LoginData(client_name, password) => login(&app_state, &client_name, password)
+                .await
+                .map(ReplyData::SessionToken),
+            _ => Err(wrong_request("login")),
         }
-        _ => Err(()),
     };
-    request_with_header(ctx, f, CLIENT_UUID_HEADER, "login").await
+    request_async(ctx, f).await
+}
+
+pub fn valid_room_name(room_name: &RoomName) -> Result<RoomName, ApiError> {
+    let name = room_name.0.trim();
+    if name.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            "Room names cannot be empty",
+        ));
+    }
+    Ok(RoomName(name.to_string()))
+}
+
+pub fn insert_room(app: &mut AppState, room_name: RoomName, owner: Option<ClientUuid>) -> RoomUuid {
+    let room = Room::new(&room_name.0, owner);
+    let room_uuid = room.uuid;
+    app.rooms.insert(room_uuid, room);
+    app.persist_room(room_uuid);
+    room_uuid
+}
+
+fn create_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_name: &RoomName,
+) -> Result<RoomUuid, ApiError> {
+    let room_name = valid_room_name(room_name)?;
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    if app.find_room(&room_name).is_some() {
+        return Err(ApiError::new(
+            ErrorCode::AlreadyExists,
+            format!("Room '{}' already exists", room_name.0),
+        ));
+    }
+    Ok(insert_room(&mut app, room_name, Some(client_uuid)))
 }
 
 pub async fn handle_create_room(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
     let f = |req_data| match req_data {
-        ReqData::CreateRoomData(room_name) => {
-            let room_uuid = RoomUuid(Uuid::new_v4());
-            app_state
-                .lock()
-                .unwrap()
-                .rooms
-                .insert(room_uuid, Room::new(&*room_name.0));
-            Ok(room_uuid)
+        ReqData::CreateRoomData(token, room_name) => {
+            create_room(&app_state, &token, &room_name).map(|uuid| ReplyData::RoomUuid(Some(uuid)))
+        }
+        _ => Err(wrong_request("create_room")),
+    };
+    request(ctx, f).await
+}
+
+fn get_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_name: &RoomName,
+) -> Result<Option<RoomUuid>, ApiError> {
+    let mut app = app_state.lock().unwrap();
+    authenticate(&mut app, token)?;
+    Ok(app.find_room(room_name))
+}
+
+/// Finds the room or creates it, under the same lock so that two users can't create it twice.
+/// Returns whether the room was created.
+fn get_or_create_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_name: &RoomName,
+) -> Result<(RoomUuid, bool), ApiError> {
+    let room_name = valid_room_name(room_name)?;
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    match app.find_room(&room_name) {
+        Some(room_uuid) => Ok((room_uuid, false)),
+        None => Ok((insert_room(&mut app, room_name, Some(client_uuid)), true)),
+    }
+}
+
+pub async fn handle_get_or_create_room(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::GetOrCreateRoomData(token, room_name) => {
+            get_or_create_room(&app_state, &token, &room_name)
+                .map(|(room_uuid, created)| ReplyData::Room(room_uuid, created))
+        }
+        _ => Err(wrong_request("get_or_create_room")),
+    };
+    request(ctx, f).await
+}
+
+/// Renames the room, which only its owner can do, and tells its members.
+fn rename_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    room_name: &RoomName,
+) -> Result<(), ApiError> {
+    let room_name = valid_room_name(room_name)?;
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    if app
+        .find_room(&room_name)
+        .is_some_and(|other| other != room_uuid)
+    {
+        return Err(ApiError::new(
+            ErrorCode::AlreadyExists,
+            format!("Room '{}' already exists", room_name.0),
+        ));
+    }
+    let room = app.rooms.get_mut(&room_uuid).unwrap();
+    room.name = room_name.clone();
+    room.touch();
+    app.persist_room(room_uuid);
+    let msg_content = format!("The room was renamed to '{}'", room_name.0);
+    app.send_to_room(ServerEvent::RoomRenamed(room_uuid, room_name), room_uuid);
+    app.log(&ChatMessage::new(SERVER_SIGNATURE, &msg_content), room_uuid);
+    Ok(())
+}
+
+pub async fn handle_rename_room(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::RenameRoomData(token, room_uuid, room_name) => {
+            rename_room(&app_state, &token, room_uuid, &room_name).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("rename_room")),
+    };
+    request(ctx, f).await
+}
+
+/// Deletes the room, which only its owner can do. Its members are told and evicted.
+pub fn delete_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    app.delete_room(room_uuid);
+    Ok(())
+}
+
+pub async fn handle_delete_room(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::DeleteRoomData(token, room_uuid) => {
+            delete_room(&app_state, &token, room_uuid).map(|_| ReplyData::Ok)
         }
-        _ => Err(()),
+        _ => Err(wrong_request("delete_room")),
     };
-    request_with_header(ctx, f, ROOM_UUID_HEADER, "create_room").await
+    request(ctx, f).await
 }
 
 pub async fn handle_get_room(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
     let f = |req_data| match req_data {
-        ReqData::GetRoomData(room_name) => {
-            let room_uuid = app_state.lock().unwrap().rooms.iter().find_map(|(k, v)| {
-                if v.name == room_name {
-                    Some(*k)
-                } else {
-                    None
-                }
-            });
-            Ok(room_uuid)
+        ReqData::GetRoomData(token, room_name) => {
+            get_room(&app_state, &token, &room_name).map(ReplyData::RoomUuid)
         }
-        _ => Err(()),
+        _ => Err(wrong_request("get_room")),
     };
-    request_with_header(ctx, f, ROOM_UUID_HEADER, "get_room").await
+    request(ctx, f).await
+}
+
+/// Whether the client may join the room, and the hash of the password they have to give if any
+fn check_admission(room: &Room, client_uuid: ClientUuid) -> Result<Option<String>, ApiError> {
+    if let Some(banned_until) = room.banned_until(&client_uuid) {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "You are banned from room '{}' until {}",
+                room.name.0, banned_until
+            ),
+        ));
+    }
+    if room.is_admitted(&client_uuid) {
+        return Ok(None);
+    }
+    if room.private {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!("Room '{}' is private, you need an invitation", room.name.0),
+        ));
+    }
+    Ok(room.password_hash.clone())
+}
+
+/// Joins the room, unless it's private or the password is missing or wrong,
+/// which the owner, members and invitees can do without.
+pub async fn join_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    password: Option<Password>,
+) -> Result<bool, ApiError> {
+    let password_hash = {
+        let mut app = app_state.lock().unwrap();
+        let client_uuid = authenticate(&mut app, token)?;
+        match app.rooms.get(&room_uuid) {
+            None => return Ok(false),
+            // Joining again changes nothing
+            Some(room) if room.contains(&client_uuid) => return Ok(true),
+            Some(room) => check_admission(room, client_uuid)?,
+        }
+    };
+    // Verifying is slow on purpose, so it's done without holding the lock
+    if let Some(password_hash) = &password_hash {
+        let password = password.ok_or_else(|| {
+            ApiError::new(ErrorCode::PasswordRequired, "The room requires a password")
+        })?;
+        if !verify_password_async(password, password_hash.clone()).await {
+            return Err(ApiError::new(
+                ErrorCode::Forbidden,
+                "Wrong password for the room",
+            ));
+        }
+    }
+    // Only now that the client may join, so that nobody else can make the logs flush
+    flush_room_log(app_state, room_uuid).await;
+    let (log_dir, history_size) = {
+        let app = app_state.lock().unwrap();
+        (app.config.log_dir(), app.config.history_size)
+    };
+    let history = read_history(&log_dir, room_uuid, None, history_size).unwrap_or_else(|e| {
+        eprintln!("Error reading history for room {}: {}", room_uuid.0, e);
+        Vec::new()
+    });
+    let mut success = false;
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    if let Some(room) = app.rooms.get_mut(&room_uuid) {
+        if room.contains(&client_uuid) {
+            // Joined meanwhile, by another request
+            return Ok(true);
+        }
+        // The room may have changed while the lock was released
+        match check_admission(room, client_uuid)? {
+            Some(current_hash) if Some(&current_hash) != password_hash.as_ref() => {
+                return Err(ApiError::new(
+                    ErrorCode::Forbidden,
+                    "The room's password just changed",
+                ))
+            }
+            _ => {}
+        }
+        room.add(client_uuid);
+        // Rooms from before owners, and those created by v0 clients, go to whoever joins first
+        if room.owner.is_none() {
+            println!("{} now owns room '{}'", client_uuid.0, room.name.0);
+            room.owner = Some(client_uuid);
+        }
+        room.touch();
+        app.persist_room(room_uuid);
+        success = true;
+    }
+    if success {
+        for entry in history {
+            app.send_to_client(entry.into_event(room_uuid), client_uuid);
+        }
+        let joined = LogEntry::Joined {
+            joined: app.users[&client_uuid].name.clone(),
+            timestamp: Utc::now(),
+        };
+        app.record_presence(joined, room_uuid);
+    }
+    Ok(success)
 }
 
 pub async fn handle_join_room(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
-    let f = |req_data| match req_data {
-        ReqData::JoinRoomData(client_name, client_uuid, room_uuid) => {
-            let mut success = false;
-            if let Some(room) = app_state.lock().unwrap().rooms.get_mut(&room_uuid) {
-                room.add(client_uuid);
-                success = true;
+    let f = |req_data| async {
+        match req_data {
+            ReqData::JoinRoomData(token, room_uuid) => {
+                join_room(&app_state, &token, room_uuid, None)
+                    .await
+                    .map(ReplyData::Success)
             }
-            if success {
-                let hello_msg = format!("{} has joined the chat", &client_name.0);
-                let hello_msg = ChatMessage::new(SERVER_SIGNATURE, &hello_msg);
-                app_state
-                    .lock()
-                    .unwrap()
-                    .send_to_room(&hello_msg, room_uuid);
+            ReqData::JoinProtectedRoomData(token, room_uuid, password) => {
+                join_room(&app_state, &token, room_uuid, Some(password))
+                    .await
+                    .map(ReplyData::Success)
             }
-            Ok(success)
+            _ => Err(wrong_request("join_room")),
         }
-        _ => Err(()),
     };
-    request_with_header(ctx, f, SUCCESS_HEADER, "join_room").await
+    request_async(ctx, f).await
+}
+
+/// Messages can only be sent as the session's user. Names being looked up regardless of case,
+/// they may have logged in with another one, so the author is set to the name they registered.
+fn check_author(
+    app: &AppState,
+    client_uuid: ClientUuid,
+    msg: &mut ChatMessage,
+) -> Result<(), ApiError> {
+    let name = &app.users[&client_uuid].name;
+    if ClientName(msg.author.clone()).normalized() != name.normalized() {
+        eprintln!(
+            "Rejecting message from {} posing as '{}'",
+            client_uuid.0, msg.author
+        );
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Messages can only be sent as yourself",
+        ));
+    }
+    msg.author = name.0.clone();
+    Ok(())
+}
+
+pub fn send_msg(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    mut msg: ChatMessage,
+    room_uuid: RoomUuid,
+) -> Result<ChatMessage, ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    // The author can only be the session's user, and the timestamp is the server's
+    check_author(&app, client_uuid, &mut msg)?;
+    check_member(&app, client_uuid, room_uuid)?;
+    if let Some(until) = app.rooms[&room_uuid].muted_until(&client_uuid) {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!("You are muted in this room until {}", until),
+        ));
+    }
+    msg.timestamp = Utc::now();
+    println!("{}", msg);
+    match app.logger.log(&msg, room_uuid) {
+        Ok(()) => {
+            app.rooms.get_mut(&room_uuid).unwrap().touch();
+            app.send_to_room(ServerEvent::Message(room_uuid, msg.clone()), room_uuid);
+            Ok(msg)
+        }
+        Err(LogError::Backpressure) => {
+            eprintln!("Log queue full, rejecting message for room {}", room_uuid.0);
+            Err(ApiError::new(ErrorCode::Unavailable, "Server is busy"))
+        }
+        Err(LogError::Closed) => Err(ApiError::new(ErrorCode::Internal, "Logger is closed")),
+    }
+}
+
+/// Sends the message to the recipient only, provided they are connected, and logs it
+/// with the rest of their conversation.
+pub fn send_direct_msg(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    msg: ChatMessage,
+    recipient: ClientUuid,
+) -> Result<ChatMessage, ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    deliver_direct_msg(&mut app, client_uuid, msg, recipient)
+}
+
+fn deliver_direct_msg(
+    app: &mut AppState,
+    client_uuid: ClientUuid,
+    mut msg: ChatMessage,
+    recipient: ClientUuid,
+) -> Result<ChatMessage, ApiError> {
+    check_author(app, client_uuid, &mut msg)?;
+    let recipient_name = match app.users.get(&recipient) {
+        Some(user) => user.name.clone(),
+        None => {
+            return Err(ApiError::new(
+                ErrorCode::NotFound,
+                format!("No user {}", recipient.0),
+            ))
+        }
+    };
+    if recipient == client_uuid {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            "You cannot message yourself",
+        ));
+    }
+    if !app.clients.contains_key(&recipient) {
+        return Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("{} is not online", recipient_name.0),
+        ));
+    }
+    msg.timestamp = Utc::now();
+    match app
+        .logger
+        .log(&msg, ConversationId::new(client_uuid, recipient))
+    {
+        Ok(()) => {
+            app.send_to_client(ServerEvent::DirectMessage(msg.clone()), recipient);
+            Ok(msg)
+        }
+        Err(LogError::Backpressure) => Err(ApiError::new(ErrorCode::Unavailable, "Server is busy")),
+        Err(LogError::Closed) => Err(ApiError::new(ErrorCode::Internal, "Logger is closed")),
+    }
+}
+
+fn direct_msg(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    recipient: &ClientName,
+    msg: ChatMessage,
+) -> Result<ChatMessage, ApiError> {
+    // Authenticated first, so that only users can tell which names exist
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    let recipient_uuid = app.find_user_by_name(recipient).ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("No user named '{}'", recipient.0),
+        )
+    })?;
+    deliver_direct_msg(&mut app, client_uuid, msg, recipient_uuid)
+}
+
+pub async fn handle_direct_msg(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::DirectMsgData(token, recipient, msg) => {
+            direct_msg(&app_state, &token, &recipient, msg).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("direct_msg")),
+    };
+    request(ctx, f).await
 }
 
 pub async fn handle_send_msg(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
     let f = |req_data| match req_data {
-        ReqData::SendMsgData(msg, room_uuid) => {
-            println!("{}", msg);
-            if log_msg(&msg, room_uuid).is_err() {
-                eprintln!("Error logging message for room {}", room_uuid.0);
-            }
-            app_state.lock().unwrap().send_to_room(&msg, room_uuid);
-            Ok(StatusCode::OK)
+        ReqData::SendMsgData(token, msg, room_uuid) => {
+            send_msg(&app_state, &token, msg, room_uuid).map(|_| ReplyData::Ok)
         }
-        _ => Err(()),
+        _ => Err(wrong_request("send_msg")),
     };
-    request(ctx, f, "send_msg").await
+    request(ctx, f).await
+}
+
+pub fn leave_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_member(&app, client_uuid, room_uuid)?;
+    app.disconnect_client_from_one(client_uuid, room_uuid);
+    Ok(())
 }
 
 pub async fn handle_leave_room(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
     let f = |req_data| match req_data {
-        ReqData::LeaveRoomData(room_uuid, client_uuid) => {
-            app_state
-                .lock()
-                .unwrap()
-                .disconnect_client_from_one(client_uuid, room_uuid);
-            Ok(StatusCode::OK)
+        ReqData::LeaveRoomData(token, room_uuid) => {
+            leave_room(&app_state, &token, room_uuid).map(|_| ReplyData::Ok)
         }
-        _ => Err(()),
+        _ => Err(wrong_request("leave_room")),
     };
-    request(ctx, f, "leave_room").await
+    request(ctx, f).await
+}
+
+pub fn exit_app(app_state: &Arc<Mutex<AppState>>, token: &SessionToken) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    app.disconnect_client_from_all(client_uuid);
+    app.close_session(token);
+    Ok(())
 }
 
 pub async fn handle_exit_app(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
     let f = |req_data| match req_data {
-        ReqData::ExitAppData(client_uuid) => {
-            app_state
-                .lock()
-                .unwrap()
-                .disconnect_client_from_all(client_uuid);
-            Ok(StatusCode::OK)
+        ReqData::ExitAppData(token) => exit_app(&app_state, &token).map(|_| ReplyData::Ok),
+        _ => Err(wrong_request("exit_app")),
+    };
+    request(ctx, f).await
+}
+
+async fn load_history(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    before: HistoryCursor,
+) -> Result<(), ApiError> {
+    let (client_uuid, is_member, log_dir, history_size) = {
+        let mut app = app_state.lock().unwrap();
+        let client_uuid = authenticate(&mut app, token)?;
+        let is_member = app
+            .rooms
+            .get(&room_uuid)
+            .map(|room| room.contains(&client_uuid));
+        let config = &app.config;
+        (
+            client_uuid,
+            is_member,
+            config.log_dir(),
+            config.history_size,
+        )
+    };
+    match is_member {
+        None => return Err(no_such_room(room_uuid)),
+        Some(false) => return Err(not_a_member(room_uuid)),
+        Some(true) => {}
+    }
+    flush_room_log(app_state, room_uuid).await;
+    match read_history(&log_dir, room_uuid, Some(before), history_size) {
+        Ok(history) => {
+            let app = app_state.lock().unwrap();
+            for entry in history {
+                app.send_to_client(entry.into_event(room_uuid), client_uuid);
+            }
+            Ok(())
         }
-        _ => Err(()),
+        Err(e) => {
+            eprintln!("Error reading history for room {}: {}", room_uuid.0, e);
+            Err(ApiError::new(ErrorCode::Internal, "Reading history failed"))
+        }
+    }
+}
+
+pub async fn handle_load_history(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| async {
+        let (token, room_uuid, before) = match req_data {
+            ReqData::LoadHistoryData(token, room_uuid, before) => {
+                (token, room_uuid, HistoryCursor::before(before))
+            }
+            ReqData::LoadHistoryPageData(token, room_uuid, before) => (token, room_uuid, before),
+            _ => return Err(wrong_request("load_history")),
+        };
+        load_history(&app_state, &token, room_uuid, before)
+            .await
+            .map(|_| ReplyData::Ok)
     };
-    request(ctx, f, "exit_app").await
+    request_async(ctx, f).await
+}
+
+pub fn heartbeat(app_state: &Arc<Mutex<AppState>>, token: &SessionToken) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    match app.clients.entry(client_uuid) {
+        Entry::Occupied(mut entry) => {
+            println!(
+                "Received heartbeat from {} ({})",
+                client_uuid.0,
+                entry.get().name.0
+            );
+            entry.get_mut().is_alive = true;
+            entry.get_mut().last_heartbeat = Instant::now();
+            Ok(())
+        }
+        Entry::Vacant(_) => Err(ApiError::new(
+            ErrorCode::NotFound,
+            "No WS connection for this session",
+        )),
+    }
 }
 
 pub async fn handle_heartbeat(ctx: Context) -> Response {
     let app_state = ctx.app_state.clone();
     let f = |req_data| match req_data {
-        ReqData::HeartbeatData(client_uuid) => {
-            match app_state.lock().unwrap().clients.entry(client_uuid) {
-                Entry::Occupied(mut entry) => {
-                    println!(
-                        "Received heartbeat from {} ({})",
-                        client_uuid.0,
-                        entry.get().name.0
-                    );
-                    entry.get_mut().is_alive = true;
-                    Ok(StatusCode::OK)
-                }
-                Entry::Vacant(_) => Ok(StatusCode::NOT_FOUND),
+        ReqData::HeartbeatData(token) => heartbeat(&app_state, &token).map(|_| ReplyData::Ok),
+        _ => Err(wrong_request("heartbeat")),
+    };
+    request(ctx, f).await
+}
+
+pub fn list_rooms(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+) -> Result<Vec<RoomInfo>, ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    let mut rooms = app
+        .rooms
+        .values()
+        .filter(|room| room.is_visible_to(&client_uuid))
+        .map(Room::info)
+        .collect::<Vec<_>>();
+    rooms.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+    Ok(rooms)
+}
+
+pub async fn handle_list_rooms(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::ListRoomsData(token) => list_rooms(&app_state, &token).map(ReplyData::Rooms),
+        _ => Err(wrong_request("list_rooms")),
+    };
+    request(ctx, f).await
+}
+
+fn describe_room(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    description: String,
+) -> Result<(), ApiError> {
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            format!(
+                "Descriptions can be at most {} characters long",
+                MAX_DESCRIPTION_LEN
+            ),
+        ));
+    }
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_moderator(&app, client_uuid, room_uuid)?;
+    app.rooms.get_mut(&room_uuid).unwrap().description = description;
+    app.persist_room(room_uuid);
+    Ok(())
+}
+
+pub async fn handle_describe_room(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::DescribeRoomData(token, room_uuid, description) => {
+            describe_room(&app_state, &token, room_uuid, description).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("describe_room")),
+    };
+    request(ctx, f).await
+}
+
+/// Lists the room's members, with whether they are connected
+pub fn list_members(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+) -> Result<Vec<MemberInfo>, ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    let heartbeat_interval = Duration::from_millis(app.config.heartbeat_interval_ms);
+    let room = app
+        .rooms
+        .get(&room_uuid)
+        .filter(|room| room.is_visible_to(&client_uuid))
+        .ok_or_else(|| no_such_room(room_uuid))?;
+    let mut members = room
+        .members
+        .iter()
+        .map(|client_uuid| MemberInfo {
+            uuid: *client_uuid,
+            name: app.users[client_uuid].name.clone(),
+            presence: app
+                .clients
+                .get(client_uuid)
+                .map_or(Presence::Offline, |client| {
+                    client.presence(heartbeat_interval)
+                }),
+            role: room.role(client_uuid),
+        })
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| a.name.0.cmp(&b.name.0));
+    Ok(members)
+}
+
+pub async fn handle_list_members(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::ListMembersData(token, room_uuid) => {
+            list_members(&app_state, &token, room_uuid).map(ReplyData::Members)
+        }
+        _ => Err(wrong_request("list_members")),
+    };
+    request(ctx, f).await
+}
+
+fn set_room_private(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    private: bool,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    app.rooms.get_mut(&room_uuid).unwrap().private = private;
+    app.persist_room(room_uuid);
+    Ok(())
+}
+
+pub async fn handle_set_room_private(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::SetRoomPrivateData(token, room_uuid, private) => {
+            set_room_private(&app_state, &token, room_uuid, private).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("set_room_private")),
+    };
+    request(ctx, f).await
+}
+
+/// Sets how much of the room's log is kept, which only its owner can do
+fn set_retention(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    retention: Option<RetentionPolicy>,
+) -> Result<(), ApiError> {
+    if retention
+        .is_some_and(|policy| policy.max_age_days == Some(0) || policy.max_messages == Some(0))
+    {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            "Retention limits must be positive",
+        ));
+    }
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    app.rooms.get_mut(&room_uuid).unwrap().retention = retention;
+    app.persist_room(room_uuid);
+    Ok(())
+}
+
+pub async fn handle_set_retention(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::SetRetentionData(token, room_uuid, retention) => {
+            set_retention(&app_state, &token, room_uuid, retention).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("set_retention")),
+    };
+    request(ctx, f).await
+}
+
+async fn set_room_password(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    password: Option<Password>,
+) -> Result<(), ApiError> {
+    {
+        let mut app = app_state.lock().unwrap();
+        let client_uuid = authenticate(&mut app, token)?;
+        check_owner(&app, client_uuid, room_uuid)?;
+    }
+    // Hashing is slow on purpose, so it's done without holding the lock
+    let password_hash =
+        match password {
+            None => None,
+            Some(password) if password.0.is_empty() => {
+                return Err(ApiError::new(
+                    ErrorCode::Invalid,
+                    "Passwords cannot be empty",
+                ))
             }
+            Some(password) => Some(hash_password_async(password).await.ok_or_else(|| {
+                ApiError::new(ErrorCode::Internal, "Hashing the password failed")
+            })?),
+        };
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    app.rooms.get_mut(&room_uuid).unwrap().password_hash = password_hash;
+    app.persist_room(room_uuid);
+    Ok(())
+}
+
+pub async fn handle_set_room_password(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| async {
+        match req_data {
+            ReqData::SetRoomPasswordData(token, room_uuid, password) => {
+                set_room_password(&app_state, &token, room_uuid, password)
+                    .await
+                    .map(|_| ReplyData::Ok)
+            }
+            _ => Err(wrong_request("set_room_password")),
+        }
+    };
+    request_async(ctx, f).await
+}
+
+/// Adds the user to the room's invite list, telling them, or takes them off it.
+fn set_invited(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    invitee: &ClientName,
+    invited: bool,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    let invitee_uuid = app.find_user_by_name(invitee).ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::NotFound,
+            format!("No user named '{}'", invitee.0),
+        )
+    })?;
+    let room = app.rooms.get_mut(&room_uuid).unwrap();
+    if invited {
+        room.invited.insert(invitee_uuid);
+    } else {
+        room.invited.remove(&invitee_uuid);
+    }
+    let room_name = room.name.clone();
+    app.persist_room(room_uuid);
+    if invited {
+        let inviter_name = app.users[&client_uuid].name.clone();
+        app.send_to_client(ServerEvent::Invited(room_name, inviter_name), invitee_uuid);
+    }
+    Ok(())
+}
+
+pub async fn handle_invite(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::InviteData(token, room_uuid, invitee) => {
+            set_invited(&app_state, &token, room_uuid, &invitee, true).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("invite")),
+    };
+    request(ctx, f).await
+}
+
+pub async fn handle_uninvite(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::UninviteData(token, room_uuid, invitee) => {
+            set_invited(&app_state, &token, room_uuid, &invitee, false).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("uninvite")),
+    };
+    request(ctx, f).await
+}
+
+/// Authenticates the moderator and resolves the user they act on, making sure they outrank them.
+fn moderation_target(
+    app: &mut AppState,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    target: &ClientName,
+) -> Result<(ClientName, ClientUuid), ApiError> {
+    let client_uuid = authenticate(app, token)?;
+    let target_uuid = app.find_user_by_name(target).ok_or_else(|| {
+        ApiError::new(ErrorCode::NotFound, format!("No user named '{}'", target.0))
+    })?;
+    check_moderator(app, client_uuid, room_uuid)?;
+    let room = &app.rooms[&room_uuid];
+    if room.role(&client_uuid) <= room.role(&target_uuid) {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!("You cannot moderate {}", app.users[&target_uuid].name.0),
+        ));
+    }
+    Ok((app.users[&client_uuid].name.clone(), target_uuid))
+}
+
+fn kick(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    target: &ClientName,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let (moderator, target_uuid) = moderation_target(&mut app, token, room_uuid, target)?;
+    let target_name = app.users[&target_uuid].name.clone();
+    if !app.rooms[&room_uuid].contains(&target_uuid) {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            format!("{} is not in the room", target_name.0),
+        ));
+    }
+    app.evict(
+        target_uuid,
+        room_uuid,
+        &format!("kicked by {}", moderator.0),
+    );
+    app.record_in_room(
+        &format!("{} was kicked by {}", target_name.0, moderator.0),
+        room_uuid,
+    );
+    Ok(())
+}
+
+pub async fn handle_kick(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::KickData(token, room_uuid, target) => {
+            kick(&app_state, &token, room_uuid, &target).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("kick")),
+    };
+    request(ctx, f).await
+}
+
+/// Bans the user for the given time, removing them from the room if they are in it
+fn ban(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    target: &ClientName,
+    minutes: u32,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let (moderator, target_uuid) = moderation_target(&mut app, token, room_uuid, target)?;
+    let target_name = app.users[&target_uuid].name.clone();
+    let room = app.rooms.get_mut(&room_uuid).unwrap();
+    if minutes == 0 {
+        room.bans.remove(&target_uuid);
+        app.persist_room(room_uuid);
+        app.record_in_room(
+            &format!("{} was unbanned by {}", target_name.0, moderator.0),
+            room_uuid,
+        );
+        return Ok(());
+    }
+    let until = Utc::now() + chrono::Duration::minutes(minutes.into());
+    room.bans.insert(target_uuid, until);
+    let is_member = room.contains(&target_uuid);
+    app.persist_room(room_uuid);
+    if is_member {
+        app.evict(
+            target_uuid,
+            room_uuid,
+            &format!("banned by {} until {}", moderator.0, until),
+        );
+    }
+    app.record_in_room(
+        &format!(
+            "{} was banned by {} for {} minutes",
+            target_name.0, moderator.0, minutes
+        ),
+        room_uuid,
+    );
+    Ok(())
+}
+
+pub async fn handle_ban(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::BanData(token, room_uuid, target, minutes) => {
+            ban(&app_state, &token, room_uuid, &target, minutes).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("ban")),
+    };
+    request(ctx, f).await
+}
+
+fn mute(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    target: &ClientName,
+    minutes: u32,
+) -> Result<(), ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let (moderator, target_uuid) = moderation_target(&mut app, token, room_uuid, target)?;
+    let target_name = app.users[&target_uuid].name.clone();
+    let room = app.rooms.get_mut(&room_uuid).unwrap();
+    let (until, record) = if minutes == 0 {
+        room.mutes.remove(&target_uuid);
+        let record = format!("{} was unmuted by {}", target_name.0, moderator.0);
+        (None, record)
+    } else {
+        let until = Utc::now() + chrono::Duration::minutes(minutes.into());
+        room.mutes.insert(target_uuid, until);
+        let record = format!(
+            "{} was muted by {} for {} minutes",
+            target_name.0, moderator.0, minutes
+        );
+        (Some(until), record)
+    };
+    app.persist_room(room_uuid);
+    app.send_to_client(ServerEvent::Muted(room_uuid, until), target_uuid);
+    app.record_in_room(&record, room_uuid);
+    Ok(())
+}
+
+pub async fn handle_mute(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::MuteData(token, room_uuid, target, minutes) => {
+            mute(&app_state, &token, room_uuid, &target, minutes).map(|_| ReplyData::Ok)
+        }
+        _ => Err(wrong_request("mute")),
+    };
+    request(ctx, f).await
+}
+
+/// Makes the user a moderator of the room or a plain member again, which only the owner can do
+fn set_role(
+    app_state: &Arc<Mutex<AppState>>,
+    token: &SessionToken,
+    room_uuid: RoomUuid,
+    target: &ClientName,
+    role: Role,
+) -> Result<(), ApiError> {
+    if role == Role::Owner {
+        return Err(ApiError::new(
+            ErrorCode::Invalid,
+            "Rooms can only have one owner",
+        ));
+    }
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    check_owner(&app, client_uuid, room_uuid)?;
+    let (_, target_uuid) = moderation_target(&mut app, token, room_uuid, target)?;
+    let target_name = app.users[&target_uuid].name.clone();
+    let room = app.rooms.get_mut(&room_uuid).unwrap();
+    match role {
+        Role::Moderator => room.moderators.insert(target_uuid),
+        _ => room.moderators.remove(&target_uuid),
+    };
+    app.persist_room(room_uuid);
+    app.record_in_room(&format!("{} is now a {}", target_name.0, role), room_uuid);
+    Ok(())
+}
+
+pub async fn handle_set_role(ctx: Context) -> Response {
+    let app_state = ctx.app_state.clone();
+    let f = |req_data| match req_data {
+        ReqData::SetRoleData(token, room_uuid, target, role) => {
+            set_role(&app_state, &token, room_uuid, &target, role).map(|_| ReplyData::Ok)
         }
-        _ => Err(()),
+        _ => Err(wrong_request("set_role")),
     };
-    request(ctx, f, "heartbeat").await
+    request(ctx, f).await
 }
@@ -0,0 +1,150 @@
+//! Requests of v0, the format from before sessions and passwords.
+//!
+//! Clients of v0 register a new user over WS, without a password, then log in by name to get the
+//! uuid they send with their requests. That uuid is a `LegacyId` of the connection which registered
+//! the user, given out to the first login only and forgotten once the connection closes, so that
+//! knowing the name isn't enough to act as them. Requests are done with a session of the user, as
+//! for other clients, and replied in headers or in the status.
+//!
+//! Messages of v0 only carry their author, which anyone can write, so they are refused.
+
+use std::collections::HashMap;
+
+use chatter::common::protocol::LegacyReqData;
+use chatter::common::{
+    ApiError, ClientName, ClientUuid, ErrorCode, RoomName, RoomUuid, SessionToken,
+    CLIENT_UUID_HEADER, ROOM_UUID_HEADER, SUCCESS_HEADER,
+};
+use hyper::StatusCode;
+use uuid::Uuid;
+
+use crate::handler::{
+    self, exit_app, heartbeat, insert_room, leave_room, response_with_code, response_with_header,
+    valid_room_name,
+};
+use crate::{AppState, Arc, Mutex, Response};
+
+pub async fn handle_request(app_state: &Arc<Mutex<AppState>>, req_data: LegacyReqData) -> Response {
+    let result = match req_data {
+        LegacyReqData::LoginData(client_name) => {
+            let legacy_id = login(&mut app_state.lock().unwrap(), &client_name);
+            return response_with_header(&legacy_id, CLIENT_UUID_HEADER);
+        }
+        LegacyReqData::GetRoomData(room_name) => {
+            let room_uuid = app_state.lock().unwrap().find_room(&room_name);
+            return response_with_header(&room_uuid, ROOM_UUID_HEADER);
+        }
+        LegacyReqData::CreateRoomData(room_name) => match create_room(app_state, &room_name) {
+            Ok(room_uuid) => return response_with_header(&room_uuid, ROOM_UUID_HEADER),
+            Err(e) => Err(e),
+        },
+        LegacyReqData::JoinRoomData(_, legacy_id, room_uuid) => {
+            match join_room(app_state, legacy_id, room_uuid).await {
+                Ok(success) => return response_with_header(&success, SUCCESS_HEADER),
+                Err(e) => Err(e),
+            }
+        }
+        LegacyReqData::SendMsgData(..) => Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Messages of v0 clients can't be authenticated, upgrade the client to send any",
+        )),
+        LegacyReqData::HeartbeatData(legacy_id) => {
+            session(app_state, legacy_id).and_then(|token| heartbeat(app_state, &token))
+        }
+        LegacyReqData::LeaveRoomData(room_uuid, legacy_id) => {
+            session(app_state, legacy_id).and_then(|token| leave_room(app_state, &token, room_uuid))
+        }
+        LegacyReqData::ExitAppData(legacy_id) => {
+            session(app_state, legacy_id).and_then(|token| exit_app(app_state, &token))
+        }
+        LegacyReqData::RegistrationData(_) => Err(ApiError::new(
+            ErrorCode::Invalid,
+            "Registrations are sent over WS",
+        )),
+    };
+    match result {
+        Ok(()) => response_with_code(StatusCode::OK),
+        Err(e) => response_with_code(e.code.status()),
+    }
+}
+
+fn unknown_client() -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, "Unknown client")
+}
+
+/// What v0 clients are known by, in place of their user's uuid
+pub struct LegacyId {
+    pub client_uuid: ClientUuid,
+    /// Whether a login already got it
+    claimed: bool,
+}
+
+pub type LegacyIdMap = HashMap<ClientUuid, LegacyId>;
+
+/// Gives a new id to the user just registered by a v0 connection
+pub fn issue_id(app: &mut AppState, client_uuid: ClientUuid) {
+    let legacy_id = LegacyId {
+        client_uuid,
+        claimed: false,
+    };
+    app.legacy_ids.insert(ClientUuid(Uuid::new_v4()), legacy_id);
+}
+
+/// The id of the user just registered under that name, to the first login after the registration
+fn login(app: &mut AppState, client_name: &ClientName) -> Option<ClientUuid> {
+    let client_uuid = app
+        .find_user_by_name(client_name)
+        .filter(|client_uuid| app.users[client_uuid].name == *client_name)?;
+    let (id, legacy_id) = app
+        .legacy_ids
+        .iter_mut()
+        .find(|(_, legacy_id)| legacy_id.client_uuid == client_uuid && !legacy_id.claimed)?;
+    legacy_id.claimed = true;
+    Some(*id)
+}
+
+/// A session of the id's user, the one they already have if any, so that each request doesn't
+/// open one. Users who got a password since can only be used by logging in with it.
+fn session(
+    app_state: &Arc<Mutex<AppState>>,
+    legacy_id: ClientUuid,
+) -> Result<SessionToken, ApiError> {
+    let mut app = app_state.lock().unwrap();
+    let client_uuid = app
+        .legacy_ids
+        .get(&legacy_id)
+        .filter(|legacy_id| legacy_id.claimed)
+        .map(|legacy_id| legacy_id.client_uuid)
+        .filter(|client_uuid| app.users[client_uuid].password_hash.is_empty())
+        .ok_or_else(unknown_client)?;
+    let token = app
+        .sessions
+        .iter()
+        .find(|(_, session)| session.client_uuid == client_uuid && !session.is_expired())
+        .map(|(token, _)| token.clone());
+    Ok(token.unwrap_or_else(|| app.open_session(client_uuid)))
+}
+
+/// Rooms had no owners in v0, so the ones created by its clients go to whoever joins them first,
+/// as do the rooms from back then. Creating a room that exists gives it, as it is only done after
+/// looking for it.
+fn create_room(
+    app_state: &Arc<Mutex<AppState>>,
+    room_name: &RoomName,
+) -> Result<RoomUuid, ApiError> {
+    let room_name = valid_room_name(room_name)?;
+    let mut app = app_state.lock().unwrap();
+    match app.find_room(&room_name) {
+        Some(room_uuid) => Ok(room_uuid),
+        None => Ok(insert_room(&mut app, room_name, None)),
+    }
+}
+
+async fn join_room(
+    app_state: &Arc<Mutex<AppState>>,
+    legacy_id: ClientUuid,
+    room_uuid: RoomUuid,
+) -> Result<bool, ApiError> {
+    let token = session(app_state, legacy_id)?;
+    handler::join_room(app_state, &token, room_uuid, None).await
+}
@@ -1,47 +1,1256 @@
-use std::fs::{self, OpenOptions};
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use chatter::common::{ChatMessage, RoomUuid};
+use chatter::common::config::Config;
+use chatter::common::{
+    ChatMessage, ClientName, ClientUuid, HistoryCursor, RetentionPolicy, RoomUuid, ServerEvent,
+};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
 
-const APP_DIR: &str = ".chatter";
-const ROOM_LOGS_DIR: &str = "room_logs";
+const LOG_EXTENSION: &str = "jsonl";
+const LEGACY_LOG_EXTENSION: &str = "log";
+const MIGRATED_LOG_EXTENSION: &str = "log.migrated";
+const ARCHIVE_EXTENSION: &str = "gz";
+/// Where the logs of deleted rooms are moved to, under the log directory
+const DELETED_ROOMS_DIR: &str = "deleted";
+/// Where the logs of private conversations are kept, under the log directory
+const CONVERSATIONS_DIR: &str = "conversations";
 
-fn app_dir_path() -> PathBuf {
-    dirs::home_dir()
-        .expect("Cannot locate home directory")
-        .join(APP_DIR)
+const MAX_ARCHIVES: usize = 5;
+
+const LOG_QUEUE_CAPACITY: usize = 1024;
+const LOG_BATCH_SIZE: usize = 64;
+const LOG_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+const LOG_FORMAT: &str = "chatter-room-log";
+/// v2 added the entries of users joining and leaving
+const LOG_FORMAT_VERSION: u32 = 2;
+
+/// When the active log of a room is rotated, see `Config`
+#[derive(Clone, Copy)]
+pub struct RotationLimits {
+    pub max_size: u64,
+    pub max_age_days: u32,
 }
 
-fn logs_dir_path() -> PathBuf {
-    app_dir_path().join(ROOM_LOGS_DIR)
+impl RotationLimits {
+    pub fn new(config: &Config) -> Self {
+        RotationLimits {
+            max_size: config.max_log_size,
+            max_age_days: config.max_log_age_days,
+        }
+    }
 }
 
-fn room_log_path(room_uuid: RoomUuid) -> PathBuf {
-    logs_dir_path().join(room_uuid.0.to_string())
+/// Record of a room log: a message, or a member coming or going
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum LogEntry {
+    Message(ChatMessage),
+    Joined {
+        joined: ClientName,
+        timestamp: DateTime<Utc>,
+    },
+    Left {
+        left: ClientName,
+        timestamp: DateTime<Utc>,
+    },
 }
 
-pub fn setup_app_dir() -> io::Result<()> {
-    let app_dir_path = app_dir_path();
-    if !app_dir_path.exists() {
-        eprintln!("Creating app directory under {:?}", &app_dir_path);
-        fs::create_dir(app_dir_path)?;
+impl LogEntry {
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            LogEntry::Message(msg) => msg.timestamp,
+            LogEntry::Joined { timestamp, .. } | LogEntry::Left { timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// The event showing the entry to the room's members
+    pub fn into_event(self, room_uuid: RoomUuid) -> ServerEvent {
+        match self {
+            LogEntry::Message(msg) => ServerEvent::Message(room_uuid, msg),
+            LogEntry::Joined { joined, timestamp } => {
+                ServerEvent::UserJoined(room_uuid, joined, timestamp)
+            }
+            LogEntry::Left { left, timestamp } => ServerEvent::UserLeft(room_uuid, left, timestamp),
+        }
+    }
+}
+
+/// First line of every room log, identifying the format of the records that follow.
+#[derive(Serialize, Deserialize)]
+struct LogHeader {
+    format: String,
+    version: u32,
+}
+
+impl LogHeader {
+    fn current() -> Self {
+        LogHeader {
+            format: LOG_FORMAT.to_string(),
+            version: LOG_FORMAT_VERSION,
+        }
+    }
+}
+
+/// The private conversation between two users, the same whichever of them sends.
+/// Its log is named after both of their uuids, the smallest first: `<uuid>_<uuid>.jsonl`.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct ConversationId(ClientUuid, ClientUuid);
+
+impl ConversationId {
+    pub fn new(a: ClientUuid, b: ClientUuid) -> Self {
+        if a <= b {
+            ConversationId(a, b)
+        } else {
+            ConversationId(b, a)
+        }
+    }
+
+    /// Reads the id back from the name of a log file
+    fn parse(stem: &str) -> Option<Self> {
+        let (a, b) = stem.split_once('_')?;
+        let a = ClientUuid(Uuid::parse_str(a).ok()?);
+        let b = ClientUuid(Uuid::parse_str(b).ok()?);
+        Some(ConversationId::new(a, b))
+    }
+}
+
+impl Display for ConversationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.0 .0, self.1 .0)
+    }
+}
+
+/// Whose log it is, which tells where its files are
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub enum LogKey {
+    /// Logged in the log directory, as `<uuid>.jsonl`
+    Room(RoomUuid),
+    /// Logged in `CONVERSATIONS_DIR`, see `ConversationId`
+    Conversation(ConversationId),
+}
+
+impl LogKey {
+    fn dir(&self, log_dir: &Path) -> PathBuf {
+        match self {
+            LogKey::Room(_) => log_dir.to_path_buf(),
+            LogKey::Conversation(_) => log_dir.join(CONVERSATIONS_DIR),
+        }
+    }
+
+    /// Name of the log files, without their extensions
+    fn stem(&self) -> String {
+        match self {
+            LogKey::Room(room_uuid) => room_uuid.0.to_string(),
+            LogKey::Conversation(conversation) => conversation.to_string(),
+        }
+    }
+}
+
+impl From<RoomUuid> for LogKey {
+    fn from(room_uuid: RoomUuid) -> Self {
+        LogKey::Room(room_uuid)
+    }
+}
+
+impl From<ConversationId> for LogKey {
+    fn from(conversation: ConversationId) -> Self {
+        LogKey::Conversation(conversation)
+    }
+}
+
+impl Display for LogKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogKey::Room(room_uuid) => write!(f, "room {}", room_uuid.0),
+            LogKey::Conversation(conversation) => write!(f, "conversation {}", conversation),
+        }
+    }
+}
+
+fn log_path(log_dir: &Path, key: LogKey) -> PathBuf {
+    key.dir(log_dir)
+        .join(key.stem())
+        .with_extension(LOG_EXTENSION)
+}
+
+/// Rotated logs: `<stem>.jsonl.1` is kept uncompressed (it's still used for history replay),
+/// older ones are gzipped as `<stem>.jsonl.<n>.gz`.
+fn archive_path(log_dir: &Path, key: LogKey, index: usize) -> PathBuf {
+    let file_name = if index == 1 {
+        format!("{}.{}.1", key.stem(), LOG_EXTENSION)
+    } else {
+        format!(
+            "{}.{}.{}.{}",
+            key.stem(),
+            LOG_EXTENSION,
+            index,
+            ARCHIVE_EXTENSION
+        )
+    };
+    key.dir(log_dir).join(file_name)
+}
+
+/// All files of a log, newest first.
+fn log_files(log_dir: &Path, key: LogKey) -> Vec<PathBuf> {
+    std::iter::once(log_path(log_dir, key))
+        .chain((1..=MAX_ARCHIVES).map(|index| archive_path(log_dir, key, index)))
+        .filter(|path| path.exists())
+        .collect()
+}
+
+/// The conversations which have a log, active or rotated
+fn logged_conversations(log_dir: &Path) -> io::Result<HashSet<ConversationId>> {
+    let dir = log_dir.join(CONVERSATIONS_DIR);
+    if !dir.exists() {
+        return Ok(HashSet::new());
+    }
+    let mut conversations = HashSet::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let stem = file_name.to_str().and_then(|name| name.split('.').next());
+        if let Some(conversation) = stem.and_then(ConversationId::parse) {
+            conversations.insert(conversation);
+        }
+    }
+    Ok(conversations)
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some(ARCHIVE_EXTENSION)
+}
+
+pub fn setup_app_dir(data_dir: &Path, log_dir: &Path) -> io::Result<()> {
+    if !data_dir.exists() {
+        eprintln!("Creating app directory under {:?}", data_dir);
+        fs::create_dir_all(data_dir)?;
     } else {
-        eprintln!("Located app directory under {:?}", app_dir_path);
+        eprintln!("Located app directory under {:?}", data_dir);
     }
 
-    let room_logs_path = logs_dir_path();
-    if !room_logs_path.exists() {
-        fs::create_dir(room_logs_path)?;
+    if !log_dir.exists() {
+        fs::create_dir_all(log_dir)?;
     }
 
     Ok(())
 }
 
-// TODO: make this run after some n messages (n != 1) to lessen the IO bound's perception for the user
-pub fn log_msg(msg: &ChatMessage, room_uuid: RoomUuid) -> io::Result<()> {
-    let path = room_log_path(room_uuid).with_extension("log");
-    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
-    writeln!(file, "{}", msg)?;
+fn write_header(writer: &mut impl Write) -> io::Result<()> {
+    writeln!(writer, "{}", serde_json::to_string(&LogHeader::current())?)
+}
+
+fn write_record(writer: &mut impl Write, entry: &LogEntry) -> io::Result<()> {
+    writeln!(writer, "{}", serde_json::to_string(entry)?)
+}
+
+fn write_log(writer: &mut impl Write, entries: &[LogEntry]) -> io::Result<()> {
+    write_header(writer)?;
+    for entry in entries {
+        write_record(writer, entry)?;
+    }
     Ok(())
 }
+
+/// Overwrites a (possibly compressed) log file with the given entries.
+/// The file is only replaced once the new one is completely written.
+fn write_log_file(path: &Path, entries: &[LogEntry]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let written = File::create(&tmp_path).and_then(|file| {
+        let file = if is_archive(path) {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            write_log(&mut encoder, entries)?;
+            encoder.finish()?
+        } else {
+            let mut writer = BufWriter::new(file);
+            write_log(&mut writer, entries)?;
+            writer.into_inner().map_err(|e| e.into_error())?
+        };
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(tmp_path, path)
+}
+
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+/// Moves the active log to `<stem>.jsonl.1`, shifting (and compressing) the older archives.
+/// The oldest archive is dropped once there are more than `MAX_ARCHIVES`.
+fn rotate(log_dir: &Path, key: LogKey) -> io::Result<()> {
+    let active_path = log_path(log_dir, key);
+    if !active_path.exists() {
+        return Ok(());
+    }
+
+    let oldest_path = archive_path(log_dir, key, MAX_ARCHIVES);
+    if oldest_path.exists() {
+        fs::remove_file(oldest_path)?;
+    }
+    for index in (2..MAX_ARCHIVES).rev() {
+        let path = archive_path(log_dir, key, index);
+        if path.exists() {
+            fs::rename(path, archive_path(log_dir, key, index + 1))?;
+        }
+    }
+    let latest_archive_path = archive_path(log_dir, key, 1);
+    if latest_archive_path.exists() {
+        compress(&latest_archive_path, &archive_path(log_dir, key, 2))?;
+        fs::remove_file(&latest_archive_path)?;
+    }
+    fs::rename(active_path, latest_archive_path)?;
+    println!("Rotated log of {}", key);
+    Ok(())
+}
+
+fn rotate_if_old(log_dir: &Path, key: LogKey, limits: RotationLimits) -> io::Result<()> {
+    let active_path = log_path(log_dir, key);
+    if !active_path.exists() {
+        return Ok(());
+    }
+    let cutoff = Utc::now() - chrono::Duration::days(limits.max_age_days.into());
+    match LogReader::open(&active_path)?.next() {
+        Some(Ok(first_entry)) if first_entry.timestamp() < cutoff => rotate(log_dir, key),
+        _ => Ok(()),
+    }
+}
+
+/// Drops entries not covered by the retention policy, newest files being kept first.
+/// Files left without any entries are removed.
+fn enforce_retention(log_dir: &Path, key: LogKey, policy: RetentionPolicy) -> io::Result<()> {
+    let cutoff = policy
+        .max_age_days
+        .map(|days| Utc::now() - chrono::Duration::days(days.into()));
+    let mut remaining = policy.max_messages.unwrap_or(usize::MAX);
+
+    for path in log_files(log_dir, key) {
+        let entries = LogReader::open(&path)?.collect::<io::Result<Vec<_>>>()?;
+        let total = entries.len();
+        let mut kept = entries
+            .into_iter()
+            .filter(|entry| cutoff.is_none_or(|cutoff| entry.timestamp() >= cutoff))
+            .collect::<Vec<_>>();
+        // Only messages count toward the limit, people joining and leaving are kept along with them
+        let mut messages = 0;
+        let first_dropped = kept.iter().rposition(|entry| {
+            if !matches!(entry, LogEntry::Message(_)) {
+                return false;
+            }
+            messages += 1;
+            messages > remaining
+        });
+        match first_dropped {
+            _ if remaining == 0 => kept.clear(),
+            Some(index) => {
+                kept.drain(..=index);
+                remaining = 0;
+            }
+            None => remaining -= messages,
+        }
+
+        if kept.is_empty() {
+            fs::remove_file(&path)?;
+        } else if kept.len() != total {
+            write_log_file(&path, &kept)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves all log files of the room out of the way, into `DELETED_ROOMS_DIR`.
+fn archive_room_logs(log_dir: &Path, room_uuid: RoomUuid) -> io::Result<()> {
+    let files = log_files(log_dir, room_uuid.into());
+    if files.is_empty() {
+        return Ok(());
+    }
+    let deleted_dir = log_dir.join(DELETED_ROOMS_DIR);
+    fs::create_dir_all(&deleted_dir)?;
+    for path in files {
+        fs::rename(&path, deleted_dir.join(path.file_name().unwrap()))?;
+    }
+    println!("Archived logs of deleted room {}", room_uuid.0);
+    Ok(())
+}
+
+/// Appends already serialized records to the log, writing the header first if the log is new.
+/// The log is rotated beforehand if it grew past the maximum size.
+fn append_records(
+    log_dir: &Path,
+    key: LogKey,
+    records: &[String],
+    limits: RotationLimits,
+) -> io::Result<()> {
+    let path = log_path(log_dir, key);
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() >= limits.max_size) {
+        rotate(log_dir, key)?;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut writer = BufWriter::new(file);
+    if is_new {
+        write_header(&mut writer)?;
+    }
+    for record in records {
+        writeln!(writer, "{}", record)?;
+    }
+    writer.flush()
+}
+
+pub enum LogError {
+    /// The log writer's queue is full, the message was not logged
+    Backpressure,
+    /// The log writer has shut down
+    Closed,
+}
+
+impl Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::Backpressure => write!(f, "log queue is full"),
+            LogError::Closed => write!(f, "log writer is closed"),
+        }
+    }
+}
+
+enum LogCommand {
+    Append(LogKey, String),
+    Flush(RoomUuid, oneshot::Sender<()>),
+    /// Sweeps a single log, so that appends to the others don't wait for the whole sweep
+    Sweep(LogKey, RetentionPolicy, oneshot::Sender<()>),
+    ArchiveRooms(Vec<RoomUuid>),
+    SetLimits(RotationLimits),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Handle to the logging task. Messages are queued and written to the room logs in batches,
+/// flushed when enough of them accumulate, periodically and on shutdown.
+#[derive(Clone)]
+pub struct Logger {
+    sender: mpsc::Sender<LogCommand>,
+    log_dir: PathBuf,
+}
+
+impl Logger {
+    pub fn spawn(log_dir: PathBuf, limits: RotationLimits) -> (Logger, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(LOG_QUEUE_CAPACITY);
+        (
+            Logger {
+                sender,
+                log_dir: log_dir.clone(),
+            },
+            tokio::spawn(run_log_writer(log_dir, limits, receiver)),
+        )
+    }
+
+    /// Queues the message for logging without blocking, in a room's log or a conversation's.
+    pub fn log(&self, msg: &ChatMessage, key: impl Into<LogKey>) -> Result<(), LogError> {
+        // Serialized the same as `LogEntry::Message`
+        let record = serde_json::to_string(msg).expect("Serializing message failed!");
+        self.queue(LogCommand::Append(key.into(), record))
+    }
+
+    /// Queues the entry for logging without blocking.
+    pub fn log_entry(&self, entry: &LogEntry, room_uuid: RoomUuid) -> Result<(), LogError> {
+        let record = serde_json::to_string(entry).expect("Serializing log entry failed!");
+        self.queue(LogCommand::Append(room_uuid.into(), record))
+    }
+
+    /// Queues the archiving of deleted rooms' logs, after the messages queued so far.
+    pub fn archive_rooms(&self, room_uuids: Vec<RoomUuid>) -> Result<(), LogError> {
+        self.queue(LogCommand::ArchiveRooms(room_uuids))
+    }
+
+    /// Changes when logs get rotated, from the next write on.
+    pub fn set_limits(&self, limits: RotationLimits) -> Result<(), LogError> {
+        self.queue(LogCommand::SetLimits(limits))
+    }
+
+    fn queue(&self, cmd: LogCommand) -> Result<(), LogError> {
+        self.sender.try_send(cmd).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => LogError::Backpressure,
+            mpsc::error::TrySendError::Closed(_) => LogError::Closed,
+        })
+    }
+
+    /// Waits until every message queued so far for the room is written to disk.
+    pub async fn flush(&self, room_uuid: RoomUuid) {
+        let (done_sender, done) = oneshot::channel();
+        if self
+            .sender
+            .send(LogCommand::Flush(room_uuid, done_sender))
+            .await
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+    }
+
+    /// Applies the retention policies, of the rooms and of every conversation, and rotates logs
+    /// that got too old. Logs are swept one at a time, messages being logged in between.
+    pub async fn sweep(
+        &self,
+        policies: Vec<(RoomUuid, RetentionPolicy)>,
+        conversation_policy: RetentionPolicy,
+    ) {
+        let log_dir = self.log_dir.clone();
+        let conversations = tokio::task::spawn_blocking(move || logged_conversations(&log_dir))
+            .await
+            .unwrap_or_else(|_| Ok(HashSet::new()))
+            .unwrap_or_else(|e| {
+                eprintln!("Error listing conversation logs: {}", e);
+                HashSet::new()
+            });
+        let policies = policies
+            .into_iter()
+            .map(|(room_uuid, policy)| (LogKey::Room(room_uuid), policy))
+            .chain(
+                conversations
+                    .into_iter()
+                    .map(|conversation| (conversation.into(), conversation_policy)),
+            );
+        for (key, policy) in policies {
+            let (done_sender, done) = oneshot::channel();
+            if self
+                .sender
+                .send(LogCommand::Sweep(key, policy, done_sender))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            let _ = done.await;
+        }
+    }
+
+    /// Flushes the queued messages and stops the logging task.
+    pub async fn shutdown(&self) {
+        let (done_sender, done) = oneshot::channel();
+        if self
+            .sender
+            .send(LogCommand::Shutdown(done_sender))
+            .await
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+    }
+}
+
+/// Writes the batch of a single log, returning how many records it had
+async fn write_batch(
+    log_dir: &Path,
+    limits: RotationLimits,
+    batches: &mut HashMap<LogKey, Vec<String>>,
+    key: LogKey,
+) -> usize {
+    match batches.remove(&key) {
+        Some(records) => {
+            let count = records.len();
+            let mut batch = HashMap::from([(key, records)]);
+            write_batches(log_dir, limits, &mut batch).await;
+            count
+        }
+        None => 0,
+    }
+}
+
+async fn write_batches(
+    log_dir: &Path,
+    limits: RotationLimits,
+    batches: &mut HashMap<LogKey, Vec<String>>,
+) {
+    if batches.is_empty() {
+        return;
+    }
+    let to_write = std::mem::take(batches);
+    let log_dir = log_dir.to_path_buf();
+    let result = tokio::task::spawn_blocking(move || {
+        let log_dir = log_dir.as_path();
+        for (key, records) in to_write {
+            if let Err(e) = append_records(log_dir, key, &records, limits) {
+                eprintln!(
+                    "Error logging {} messages for {}: {}",
+                    records.len(),
+                    key,
+                    e
+                );
+            }
+        }
+    })
+    .await;
+    if result.is_err() {
+        eprintln!("Log writer batch panicked!");
+    }
+}
+
+async fn sweep_log(log_dir: &Path, limits: RotationLimits, key: LogKey, policy: RetentionPolicy) {
+    let log_dir = log_dir.to_path_buf();
+    let result = tokio::task::spawn_blocking(move || {
+        if let Err(e) = enforce_retention(&log_dir, key, policy)
+            .and_then(|_| rotate_if_old(&log_dir, key, limits))
+        {
+            eprintln!("Error sweeping logs of {}: {}", key, e);
+        }
+    })
+    .await;
+    if result.is_err() {
+        eprintln!("Log sweep panicked!");
+    }
+}
+
+async fn archive_logs(log_dir: &Path, room_uuids: Vec<RoomUuid>) {
+    let log_dir = log_dir.to_path_buf();
+    let result = tokio::task::spawn_blocking(move || {
+        for room_uuid in room_uuids {
+            if let Err(e) = archive_room_logs(&log_dir, room_uuid) {
+                eprintln!("Error archiving logs of room {}: {}", room_uuid.0, e);
+            }
+        }
+    })
+    .await;
+    if result.is_err() {
+        eprintln!("Log archiving panicked!");
+    }
+}
+
+async fn run_log_writer(
+    log_dir: PathBuf,
+    mut limits: RotationLimits,
+    mut receiver: mpsc::Receiver<LogCommand>,
+) {
+    let mut batches: HashMap<LogKey, Vec<String>> = HashMap::new();
+    let mut pending = 0;
+    let mut flush_interval = tokio::time::interval(LOG_FLUSH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            cmd = receiver.recv() => match cmd {
+                Some(LogCommand::Append(key, record)) => {
+                    batches.entry(key).or_default().push(record);
+                    pending += 1;
+                    if pending >= LOG_BATCH_SIZE {
+                        write_batches(&log_dir, limits, &mut batches).await;
+                        pending = 0;
+                    }
+                }
+                Some(LogCommand::Flush(room_uuid, done)) => {
+                    // The other rooms' batches keep waiting for their turn
+                    pending -= write_batch(&log_dir, limits, &mut batches, room_uuid.into()).await;
+                    let _ = done.send(());
+                }
+                Some(LogCommand::Sweep(key, policy, done)) => {
+                    // File operations are done here so they never race with the writes
+                    pending -= write_batch(&log_dir, limits, &mut batches, key).await;
+                    sweep_log(&log_dir, limits, key, policy).await;
+                    let _ = done.send(());
+                }
+                Some(LogCommand::ArchiveRooms(room_uuids)) => {
+                    write_batches(&log_dir, limits, &mut batches).await;
+                    pending = 0;
+                    archive_logs(&log_dir, room_uuids).await;
+                }
+                Some(LogCommand::SetLimits(new_limits)) => limits = new_limits,
+                Some(LogCommand::Shutdown(done)) => {
+                    write_batches(&log_dir, limits, &mut batches).await;
+                    let _ = done.send(());
+                    return;
+                }
+                None => {
+                    write_batches(&log_dir, limits, &mut batches).await;
+                    return;
+                }
+            },
+            _ = flush_interval.tick() => {
+                write_batches(&log_dir, limits, &mut batches).await;
+                pending = 0;
+            }
+        }
+    }
+}
+
+/// Streams the messages of a room log, oldest first.
+pub struct LogReader {
+    lines: Lines<BufReader<Box<dyn Read + Send>>>,
+    pending: Option<String>,
+}
+
+impl LogReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let input: Box<dyn Read + Send> = if is_archive(path) {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let mut lines = BufReader::new(input).lines();
+        let mut pending = None;
+        if let Some(first_line) = lines.next() {
+            let first_line = first_line?;
+            match serde_json::from_str::<LogHeader>(&first_line) {
+                Ok(header) if header.format != LOG_FORMAT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown log format '{}'", header.format),
+                    ))
+                }
+                Ok(header) if header.version > LOG_FORMAT_VERSION => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported log format version {}", header.version),
+                    ))
+                }
+                Ok(_) => {}
+                // Logs written before the header was introduced start with a record right away
+                Err(_) => pending = Some(first_line),
+            }
+        }
+        Ok(LogReader { lines, pending })
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = io::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.pending.take() {
+            Some(line) => line,
+            None => match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        Some(serde_json::from_str(&line).map_err(io::Error::from))
+    }
+}
+
+/// Reads back the last `count` entries of a room's log, oldest first.
+/// If `before` is given, only entries before the cursor are considered.
+/// Only the active log and the most recent (uncompressed) archive are looked at.
+pub fn read_history(
+    log_dir: &Path,
+    room_uuid: RoomUuid,
+    before: Option<HistoryCursor>,
+    count: usize,
+) -> io::Result<Vec<LogEntry>> {
+    read_entries(log_dir, room_uuid, before, count, Some)
+}
+
+/// Same as `read_history`, for the messages only
+pub fn read_messages(
+    log_dir: &Path,
+    room_uuid: RoomUuid,
+    before: Option<HistoryCursor>,
+    count: usize,
+) -> io::Result<Vec<ChatMessage>> {
+    read_entries(log_dir, room_uuid, before, count, |entry| match entry {
+        LogEntry::Message(msg) => Some(msg),
+        _ => None,
+    })
+}
+
+fn read_entries<T>(
+    log_dir: &Path,
+    room_uuid: RoomUuid,
+    before: Option<HistoryCursor>,
+    count: usize,
+    select: impl Fn(LogEntry) -> Option<T>,
+) -> io::Result<Vec<T>> {
+    let mut entries = Vec::new();
+    let key = LogKey::Room(room_uuid);
+    for path in [archive_path(log_dir, key, 1), log_path(log_dir, key)] {
+        if !path.exists() {
+            continue;
+        }
+        for record in LogReader::open(&path)? {
+            match record {
+                Ok(entry) if before.is_none_or(|before| before.covers(entry.timestamp())) => {
+                    let timestamp = entry.timestamp();
+                    if let Some(selected) = select(entry) {
+                        entries.push((timestamp, selected));
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!(
+                    "Skipping malformed log entry in room {}: {}",
+                    room_uuid.0, e
+                ),
+            }
+        }
+    }
+    if let Some(before) = before {
+        // The latest entries logged at the cursor's time were on the previous page
+        let mut skip = before.skip;
+        entries.reverse();
+        entries.retain(|(timestamp, _)| {
+            let seen = skip > 0 && *timestamp == before.timestamp;
+            if seen {
+                skip -= 1;
+            }
+            !seen
+        });
+        entries.reverse();
+    }
+    let skip = entries.len().saturating_sub(count);
+    Ok(entries
+        .into_iter()
+        .skip(skip)
+        .map(|(_, selected)| selected)
+        .collect())
+}
+
+/// Parses a line written by the legacy `Display`-based logger: `[timestamp] author: contents`.
+fn parse_legacy_line(line: &str) -> Option<ChatMessage> {
+    let (timestamp, rest) = line.strip_prefix('[')?.split_once("] ")?;
+    let timestamp = NaiveDateTime::parse_from_str(
+        timestamp.strip_suffix(" UTC")?,
+        "%Y-%m-%d %H:%M:%S%.f",
+    )
+    .ok()?;
+    let (author, contents) = rest.split_once(": ")?;
+    Some(ChatMessage {
+        author: author.to_string(),
+        contents: contents.to_string(),
+        timestamp: Utc.from_utc_datetime(&timestamp),
+    })
+}
+
+fn read_legacy_log(path: &Path) -> io::Result<Vec<ChatMessage>> {
+    let mut msgs: Vec<ChatMessage> = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        match parse_legacy_line(&line) {
+            Some(msg) => msgs.push(msg),
+            // Messages containing newlines were split across several lines
+            None => match msgs.last_mut() {
+                Some(last) => {
+                    last.contents.push('\n');
+                    last.contents.push_str(&line);
+                }
+                None => eprintln!("Skipping unparsable line in {:?}", path),
+            },
+        }
+    }
+    Ok(msgs)
+}
+
+/// Converts a legacy `<uuid>.log` file into the current format. Messages logged in the
+/// current format in the meantime are kept after the migrated ones.
+fn migrate_legacy_log(legacy_path: &Path) -> io::Result<usize> {
+    let msgs = read_legacy_log(legacy_path)?;
+    let path = legacy_path.with_extension(LOG_EXTENSION);
+    let tmp_path = legacy_path.with_extension("jsonl.tmp");
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    write_header(&mut writer)?;
+    for msg in &msgs {
+        write_record(&mut writer, &LogEntry::Message(msg.clone()))?;
+    }
+    if path.exists() {
+        for entry in LogReader::open(&path)? {
+            write_record(&mut writer, &entry?)?;
+        }
+    }
+    writer.into_inner()?.sync_all()?;
+
+    fs::rename(tmp_path, path)?;
+    fs::rename(
+        legacy_path,
+        legacy_path.with_extension(MIGRATED_LOG_EXTENSION),
+    )?;
+    Ok(msgs.len())
+}
+
+/// One-shot migration of every legacy room log. The old files are kept with a `.log.migrated` extension.
+pub fn migrate_legacy_logs(log_dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(log_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(LEGACY_LOG_EXTENSION) {
+            continue;
+        }
+        let count = migrate_legacy_log(&path)?;
+        println!("Migrated {} messages from {:?}", count, path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatter::common::test_dir;
+
+    /// The messages' contents, with `+<user>` for users joining and `-<user>` for users leaving
+    fn contents(entries: &[LogEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|entry| match entry {
+                LogEntry::Message(msg) => msg.contents.clone(),
+                LogEntry::Joined { joined, .. } => format!("+{}", joined.0),
+                LogEntry::Left { left, .. } => format!("-{}", left.0),
+            })
+            .collect()
+    }
+
+    fn limits() -> RotationLimits {
+        RotationLimits::new(&Config::default())
+    }
+
+    fn read_file(path: &Path) -> Vec<LogEntry> {
+        LogReader::open(path)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    fn message(contents: &str, days_ago: i64) -> LogEntry {
+        LogEntry::Message(ChatMessage {
+            author: "alice".to_string(),
+            contents: contents.to_string(),
+            timestamp: Utc::now() - chrono::Duration::days(days_ago),
+        })
+    }
+
+    #[test]
+    fn parses_legacy_lines() {
+        let msg = parse_legacy_line("[2022-05-01 10:00:00.123 UTC] alice: hi: there").unwrap();
+        assert_eq!(msg.author, "alice");
+        assert_eq!(msg.contents, "hi: there");
+        assert_eq!(msg.timestamp.to_rfc3339(), "2022-05-01T10:00:00.123+00:00");
+
+        assert!(parse_legacy_line("continued message").is_none());
+        assert!(parse_legacy_line("[not a date] alice: hi").is_none());
+        assert!(parse_legacy_line("[2022-05-01 10:00:00 UTC] no separator").is_none());
+    }
+
+    #[test]
+    fn reads_multiline_legacy_messages() {
+        let dir = test_dir();
+        let path = dir.join("room.log");
+        fs::write(
+            &path,
+            "orphan line\n\
+             [2022-05-01 10:00:00 UTC] alice: first\n\
+             second line\n\
+             [2022-05-01 10:00:01 UTC] bob: reply\n",
+        )
+        .unwrap();
+
+        let msgs = read_legacy_log(&path).unwrap();
+        let texts = msgs
+            .iter()
+            .map(|msg| msg.contents.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["first\nsecond line", "reply"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_legacy_logs() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let legacy_path = log_path(&dir, room_uuid.into()).with_extension(LEGACY_LOG_EXTENSION);
+        fs::write(&legacy_path, "[2022-05-01 10:00:00 UTC] alice: old\n").unwrap();
+        // Logged since the server was upgraded, before the migration
+        append_records(
+            &dir,
+            room_uuid.into(),
+            &[serde_json::to_string(&ChatMessage::new("bob", "new")).unwrap()],
+            limits(),
+        )
+        .unwrap();
+
+        migrate_legacy_logs(&dir).unwrap();
+
+        let path = log_path(&dir, room_uuid.into());
+        let msgs = LogReader::open(&path)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(contents(&msgs), ["old", "new"]);
+        assert!(!legacy_path.exists());
+        assert!(legacy_path.with_extension(MIGRATED_LOG_EXTENSION).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_logs_by_their_header() {
+        let dir = test_dir();
+        let record = serde_json::to_string(&ChatMessage::new("alice", "hi")).unwrap();
+
+        // Logs from before the header was introduced
+        let headerless = dir.join("headerless.jsonl");
+        fs::write(&headerless, format!("{}\n", record)).unwrap();
+        let msgs = LogReader::open(&headerless)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(contents(&msgs), ["hi"]);
+
+        let newer = dir.join("newer.jsonl");
+        let header = LogHeader {
+            format: LOG_FORMAT.to_string(),
+            version: LOG_FORMAT_VERSION + 1,
+        };
+        let header = serde_json::to_string(&header).unwrap();
+        fs::write(&newer, format!("{}\n{}\n", header, record)).unwrap();
+        assert!(LogReader::open(&newer).is_err());
+
+        let unknown = dir.join("unknown.jsonl");
+        fs::write(&unknown, "{\"format\":\"other\",\"version\":1}\n").unwrap();
+        assert!(LogReader::open(&unknown).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pages_history_through_shared_timestamps() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let at = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+        let records = [0, 0, 1, 1, 1, 1, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, secs)| ChatMessage {
+                author: "alice".to_string(),
+                contents: format!("m{}", i),
+                timestamp: at(*secs),
+            })
+            .map(|msg| serde_json::to_string(&msg).unwrap())
+            .collect::<Vec<_>>();
+        append_records(&dir, room_uuid.into(), &records, limits()).unwrap();
+
+        let latest = read_history(&dir, room_uuid, None, 3).unwrap();
+        assert_eq!(contents(&latest), ["m4", "m5", "m6"]);
+        // The client saw m4 and m5 at second 1, but not m2 and m3
+        let mut cursor = None;
+        for entry in &latest {
+            cursor = Some(HistoryCursor::see(cursor, entry.timestamp()));
+        }
+        assert_eq!(
+            cursor,
+            Some(HistoryCursor {
+                timestamp: at(1),
+                skip: 2
+            })
+        );
+        let earlier = read_history(&dir, room_uuid, cursor, 3).unwrap();
+        assert_eq!(contents(&earlier), ["m1", "m2", "m3"]);
+
+        let before = read_history(&dir, room_uuid, Some(HistoryCursor::before(at(1))), 3).unwrap();
+        assert_eq!(contents(&before), ["m0", "m1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_compressed_log_files() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let path = archive_path(&dir, room_uuid.into(), 2);
+        write_log_file(&path, &[message("a", 0), message("b", 0)]).unwrap();
+
+        assert_eq!(contents(&read_file(&path)), ["a", "b"]);
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_logs_past_the_size_limit() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let limits = RotationLimits {
+            max_size: 1,
+            max_age_days: 7,
+        };
+        for i in 0..MAX_ARCHIVES + 2 {
+            let record = serde_json::to_string(&message(&format!("m{}", i), 0)).unwrap();
+            append_records(&dir, room_uuid.into(), &[record], limits).unwrap();
+        }
+
+        // The oldest message fell off the last archive
+        let files = log_files(&dir, room_uuid.into());
+        assert_eq!(files.len(), MAX_ARCHIVES + 1);
+        let entries = files
+            .iter()
+            .rev()
+            .flat_map(|path| read_file(path))
+            .collect::<Vec<_>>();
+        let expected = (1..MAX_ARCHIVES + 2).map(|i| format!("m{}", i));
+        assert!(contents(&entries).into_iter().eq(expected));
+        assert!(!is_archive(&archive_path(&dir, room_uuid.into(), 1)));
+        assert!(is_archive(&archive_path(&dir, room_uuid.into(), 2)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_old_logs() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let path = log_path(&dir, room_uuid.into());
+        write_log_file(&path, &[message("recent", 1)]).unwrap();
+        rotate_if_old(&dir, room_uuid.into(), limits()).unwrap();
+        assert!(path.exists());
+
+        write_log_file(&path, &[message("old", 8)]).unwrap();
+        rotate_if_old(&dir, room_uuid.into(), limits()).unwrap();
+        assert!(!path.exists());
+        let archive = read_file(&archive_path(&dir, room_uuid.into(), 1));
+        assert_eq!(contents(&archive), ["old"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trims_logs_by_age_and_count() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let archive_path = archive_path(&dir, room_uuid.into(), 2);
+        let path = log_path(&dir, room_uuid.into());
+        write_log_file(&archive_path, &[message("m0", 30), message("m1", 20)]).unwrap();
+        write_log_file(&path, &[message("m2", 5), message("m3", 1)]).unwrap();
+
+        let by_age = RetentionPolicy {
+            max_age_days: Some(25),
+            max_messages: None,
+        };
+        enforce_retention(&dir, room_uuid.into(), by_age).unwrap();
+        assert_eq!(contents(&read_file(&archive_path)), ["m1"]);
+        assert_eq!(contents(&read_file(&path)), ["m2", "m3"]);
+
+        let by_count = RetentionPolicy {
+            max_age_days: None,
+            max_messages: Some(1),
+        };
+        enforce_retention(&dir, room_uuid.into(), by_count).unwrap();
+        assert!(!archive_path.exists());
+        assert_eq!(contents(&read_file(&path)), ["m3"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn counts_only_messages_toward_retention() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let path = log_path(&dir, room_uuid.into());
+        let joined = |name: &str| LogEntry::Joined {
+            joined: ClientName(name.to_string()),
+            timestamp: Utc::now(),
+        };
+        let entries = [
+            message("m1", 0),
+            joined("bob"),
+            message("m2", 0),
+            joined("carol"),
+            joined("dave"),
+            message("m3", 0),
+        ];
+        write_log_file(&path, &entries).unwrap();
+
+        let by_count = RetentionPolicy {
+            max_age_days: None,
+            max_messages: Some(2),
+        };
+        enforce_retention(&dir, room_uuid.into(), by_count).unwrap();
+        assert_eq!(
+            contents(&read_file(&path)),
+            ["+bob", "m2", "+carol", "+dave", "m3"]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn logs_users_joining_and_leaving() {
+        let dir = test_dir();
+        let room_uuid = RoomUuid(Uuid::new_v4());
+        let name = ClientName("bob".to_string());
+        let entries = [
+            LogEntry::Joined {
+                joined: name.clone(),
+                timestamp: Utc::now(),
+            },
+            message("hi", 0),
+            LogEntry::Left {
+                left: name,
+                timestamp: Utc::now(),
+            },
+        ];
+        write_log_file(&log_path(&dir, room_uuid.into()), &entries).unwrap();
+
+        let history = read_history(&dir, room_uuid, None, 10).unwrap();
+        assert_eq!(contents(&history), ["+bob", "hi", "-bob"]);
+        assert!(matches!(
+            history[0].clone().into_event(room_uuid),
+            ServerEvent::UserJoined(_, name, _) if name.0 == "bob"
+        ));
+        let msgs = read_messages(&dir, room_uuid, None, 10).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].contents, "hi");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conversation_id_is_the_same_either_way() {
+        let alice = ClientUuid(Uuid::new_v4());
+        let bob = ClientUuid(Uuid::new_v4());
+        assert!(ConversationId::new(alice, bob) == ConversationId::new(bob, alice));
+        assert!(ConversationId::new(alice, bob) != ConversationId::new(alice, alice));
+    }
+
+    #[test]
+    fn names_conversation_logs_after_both_users() {
+        let dir = test_dir();
+        let alice = ClientUuid(Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap());
+        let bob = ClientUuid(Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap());
+        let conversation = ConversationId::new(bob, alice);
+        assert_eq!(
+            log_path(&dir, conversation.into()),
+            dir.join(CONVERSATIONS_DIR).join(
+                "00000000-0000-0000-0000-000000000001_00000000-0000-0000-0000-000000000002.jsonl"
+            )
+        );
+        assert!(ConversationId::parse(&conversation.to_string()) == Some(conversation));
+        assert!(ConversationId::parse("not_a conversation").is_none());
+
+        let record = serde_json::to_string(&message("hi", 0)).unwrap();
+        append_records(&dir, conversation.into(), &[record], limits()).unwrap();
+        assert!(logged_conversations(&dir).unwrap() == HashSet::from([conversation]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweeps_conversation_logs() {
+        let dir = test_dir();
+        let conversation =
+            ConversationId::new(ClientUuid(Uuid::new_v4()), ClientUuid(Uuid::new_v4()));
+        let path = log_path(&dir, conversation.into());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_log_file(&path, &[message("m1", 10), message("m2", 1)]).unwrap();
+
+        let policy = RetentionPolicy {
+            max_age_days: Some(5),
+            max_messages: None,
+        };
+        let (logger, writer) = Logger::spawn(dir.clone(), limits());
+        logger.sweep(Vec::new(), policy).await;
+        logger.shutdown().await;
+        writer.await.unwrap();
+        assert_eq!(contents(&read_file(&path)), ["m2"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
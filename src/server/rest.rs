@@ -0,0 +1,208 @@
+//! REST-style routes over rooms, for scripts and other tools.
+//!
+//! Unlike the RPC endpoints, these take no `ReqData`: the session token is sent in the
+//! `Authorization: Bearer <token>` header, the resource is identified by the path, and bodies are
+//! plain JSON. Errors are replied as an `ApiError`.
+
+use chatter::common::{
+    ApiError, ChatMessage, ClientUuid, ErrorCode, HistoryCursor, MemberInfo, Room, RoomInfo,
+    RoomUuid, SessionToken,
+};
+use chrono::{DateTime, Utc};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::handler::{self, authenticate, check_member, flush_room_log, no_such_room};
+use crate::logging::read_messages;
+use crate::{Context, Response};
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Deserialize)]
+struct NewMessage {
+    contents: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesQuery {
+    before: Option<DateTime<Utc>>,
+    /// Includes the messages sent at `before` too, except the latest `skip` ones
+    skip: Option<usize>,
+}
+
+pub fn json_response<T: Serialize>(code: StatusCode, value: &T) -> Response {
+    hyper::Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(value).unwrap().into())
+        .unwrap()
+}
+
+pub fn respond<T: Serialize>(code: StatusCode, result: Result<T, ApiError>) -> Response {
+    match result {
+        Ok(value) => json_response(code, &value),
+        Err(e) => json_response(e.code.status(), &e),
+    }
+}
+
+pub fn no_content(result: Result<(), ApiError>) -> Response {
+    match result {
+        Ok(()) => hyper::Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(hyper::Body::empty())
+            .unwrap(),
+        Err(e) => json_response(e.code.status(), &e),
+    }
+}
+
+pub fn bearer_token(ctx: &Context) -> Result<SessionToken, ApiError> {
+    ctx.req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+        .map(|token| SessionToken(token.to_string()))
+        .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Missing bearer token"))
+}
+
+pub fn uuid_param(ctx: &Context, name: &str) -> Result<Uuid, ApiError> {
+    ctx.params
+        .find(name)
+        .and_then(|value| Uuid::parse_str(value).ok())
+        .ok_or_else(|| ApiError::new(ErrorCode::Invalid, format!("Invalid {} in path", name)))
+}
+
+/// Name of the session's user, which messages are sent as
+fn author_name(ctx: &Context, token: &SessionToken) -> Result<String, ApiError> {
+    let mut app = ctx.app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, token)?;
+    Ok(app.users[&client_uuid].name.0.clone())
+}
+
+/// Authenticates the request and reads the room out of the path
+fn room_request(ctx: &Context) -> Result<(ClientUuid, RoomUuid), ApiError> {
+    let token = bearer_token(ctx)?;
+    let room_uuid = RoomUuid(uuid_param(ctx, "id")?);
+    let mut app = ctx.app_state.lock().unwrap();
+    let client_uuid = authenticate(&mut app, &token)?;
+    Ok((client_uuid, room_uuid))
+}
+
+fn list_rooms(ctx: &Context) -> Result<Vec<RoomInfo>, ApiError> {
+    handler::list_rooms(&ctx.app_state, &bearer_token(ctx)?)
+}
+
+pub async fn handle_list_rooms(ctx: Context) -> Response {
+    respond(StatusCode::OK, list_rooms(&ctx))
+}
+
+fn get_room(ctx: &Context) -> Result<RoomInfo, ApiError> {
+    let (client_uuid, room_uuid) = room_request(ctx)?;
+    let app = ctx.app_state.lock().unwrap();
+    app.rooms
+        .get(&room_uuid)
+        .filter(|room| room.is_visible_to(&client_uuid))
+        .map(Room::info)
+        .ok_or_else(|| no_such_room(room_uuid))
+}
+
+pub async fn handle_get_room(ctx: Context) -> Response {
+    respond(StatusCode::OK, get_room(&ctx))
+}
+
+fn list_members(ctx: &Context) -> Result<Vec<MemberInfo>, ApiError> {
+    let token = bearer_token(ctx)?;
+    let room_uuid = RoomUuid(uuid_param(ctx, "id")?);
+    handler::list_members(&ctx.app_state, &token, room_uuid)
+}
+
+pub async fn handle_list_members(ctx: Context) -> Response {
+    respond(StatusCode::OK, list_members(&ctx))
+}
+
+/// Returns the messages of the room, the latest ones or those before the `before` query parameter
+/// (and `skip`, see `MessagesQuery`).
+async fn list_messages(ctx: &Context) -> Result<Vec<ChatMessage>, ApiError> {
+    let query: MessagesQuery = serde_urlencoded::from_str(ctx.req.uri().query().unwrap_or(""))
+        .map_err(|e| ApiError::new(ErrorCode::Invalid, format!("Invalid query: {}", e)))?;
+    let (client_uuid, room_uuid) = room_request(ctx)?;
+    let (log_dir, history_size) = {
+        let app = ctx.app_state.lock().unwrap();
+        check_member(&app, client_uuid, room_uuid)?;
+        (app.config.log_dir(), app.config.history_size)
+    };
+    flush_room_log(&ctx.app_state, room_uuid).await;
+    let before = query.before.map(|timestamp| HistoryCursor {
+        timestamp,
+        skip: query.skip.unwrap_or(usize::MAX),
+    });
+    read_messages(&log_dir, room_uuid, before, history_size).map_err(|e| {
+        eprintln!("Error reading history for room {}: {}", room_uuid.0, e);
+        ApiError::new(ErrorCode::Internal, "Reading history failed")
+    })
+}
+
+pub async fn handle_list_messages(ctx: Context) -> Response {
+    respond(StatusCode::OK, list_messages(&ctx).await)
+}
+
+/// Sends the message to the room as the session's user and replies with it, as it was sent.
+async fn post_message(ctx: &mut Context) -> Result<ChatMessage, ApiError> {
+    let token = bearer_token(ctx)?;
+    let room_uuid = RoomUuid(uuid_param(ctx, "id")?);
+    let new_msg: NewMessage = ctx
+        .body_json()
+        .await
+        .map_err(|e| ApiError::new(ErrorCode::Invalid, format!("Invalid message: {}", e)))?;
+    let msg = ChatMessage::new(&author_name(ctx, &token)?, &new_msg.contents);
+    handler::send_msg(&ctx.app_state, &token, msg, room_uuid)
+}
+
+pub async fn handle_post_message(mut ctx: Context) -> Response {
+    respond(StatusCode::CREATED, post_message(&mut ctx).await)
+}
+
+fn delete_room(ctx: &Context) -> Result<(), ApiError> {
+    let token = bearer_token(ctx)?;
+    let room_uuid = RoomUuid(uuid_param(ctx, "id")?);
+    handler::delete_room(&ctx.app_state, &token, room_uuid)
+}
+
+pub async fn handle_delete_room(ctx: Context) -> Response {
+    no_content(delete_room(&ctx))
+}
+
+/// Sends a private message to the user, as the session's user, and replies with it as it was sent.
+async fn post_direct_message(ctx: &mut Context) -> Result<ChatMessage, ApiError> {
+    let token = bearer_token(ctx)?;
+    let recipient = ClientUuid(uuid_param(ctx, "id")?);
+    let new_msg: NewMessage = ctx
+        .body_json()
+        .await
+        .map_err(|e| ApiError::new(ErrorCode::Invalid, format!("Invalid message: {}", e)))?;
+    let msg = ChatMessage::new(&author_name(ctx, &token)?, &new_msg.contents);
+    handler::send_direct_msg(&ctx.app_state, &token, msg, recipient)
+}
+
+pub async fn handle_post_direct_message(mut ctx: Context) -> Response {
+    respond(StatusCode::CREATED, post_direct_message(&mut ctx).await)
+}
+
+/// Removes the member from the room. Members can only remove themselves.
+fn remove_member(ctx: &Context) -> Result<(), ApiError> {
+    let token = bearer_token(ctx)?;
+    let (client_uuid, room_uuid) = room_request(ctx)?;
+    if ClientUuid(uuid_param(ctx, "client")?) != client_uuid {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Only you can remove yourself from a room",
+        ));
+    }
+    handler::leave_room(&ctx.app_state, &token, room_uuid)
+}
+
+pub async fn handle_remove_member(ctx: Context) -> Response {
+    no_content(remove_member(&ctx))
+}
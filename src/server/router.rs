@@ -2,11 +2,14 @@ use std::collections::HashMap;
 
 use async_trait::async_trait;
 use futures::future::Future;
-use hyper::Method;
+use hyper::header::UPGRADE;
+use hyper::{Body, Method, Request};
 use reqwest::StatusCode;
 use route_recognizer::{Match, Params, Router as InternalRouter};
 
-use crate::handler::response_with_code;
+use chatter::common::{ApiError, ErrorCode};
+
+use crate::handler::{error_response, response_with_code};
 use crate::{Context, Response};
 
 #[async_trait]
@@ -33,6 +36,7 @@ pub struct RouterMatch<'a> {
 
 pub struct Router {
     method_map: HashMap<Method, InternalRouter<Box<dyn Handler>>>,
+    upgrade_map: InternalRouter<Box<dyn Handler>>,
 }
 
 impl Default for Router {
@@ -45,27 +49,56 @@ impl Router {
     pub fn new() -> Router {
         Router {
             method_map: HashMap::new(),
+            upgrade_map: InternalRouter::new(),
         }
     }
 
     pub fn get(&mut self, path: &str, handler: Box<dyn Handler>) {
         self.method_map
             .entry(Method::GET)
-            .or_insert_with(InternalRouter::new)
+            .or_default()
             .add(path, handler)
     }
 
     pub fn post(&mut self, path: &str, handler: Box<dyn Handler>) {
         self.method_map
             .entry(Method::POST)
-            .or_insert_with(InternalRouter::new)
+            .or_default()
+            .add(path, handler)
+    }
+
+    pub fn delete(&mut self, path: &str, handler: Box<dyn Handler>) {
+        self.method_map
+            .entry(Method::DELETE)
+            .or_default()
             .add(path, handler)
     }
 
-    pub fn route(&self, path: &str, method: &Method) -> RouterMatch<'_> {
+    /// Routes for `GET` requests asking to upgrade the connection to a WebSocket.
+    /// Plain requests to these paths get `426 Upgrade Required`.
+    pub fn upgrade(&mut self, path: &str, handler: Box<dyn Handler>) {
+        self.upgrade_map.add(path, handler)
+    }
+
+    pub fn route(&self, req: &Request<Body>) -> RouterMatch<'_> {
+        let path = req.uri().path();
+        if let Ok(Match { handler, params }) = self.upgrade_map.recognize(path) {
+            return if req.method() == Method::GET && is_ws_upgrade(req) {
+                RouterMatch {
+                    handler: &**handler,
+                    params,
+                }
+            } else {
+                RouterMatch {
+                    handler: &|_| async move { response_with_code(StatusCode::UPGRADE_REQUIRED) },
+                    params: Params::new(),
+                }
+            };
+        }
+
         if let Some(Match { handler, params }) = self
             .method_map
-            .get(method)
+            .get(req.method())
             .and_then(|r| r.recognize(path).ok())
         {
             RouterMatch {
@@ -74,13 +107,22 @@ impl Router {
             }
         } else {
             RouterMatch {
-                handler: &|_| async move { response_with_code(StatusCode::NOT_FOUND) },
+                handler: &|_| async move {
+                    error_response(ApiError::new(ErrorCode::NotFound, "No such endpoint"))
+                },
                 params: Params::new(),
             }
         }
     }
 }
 
+fn is_ws_upgrade(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
 pub trait IntoResponse: Send + Sized {
     fn into_response(self) -> Response;
 }
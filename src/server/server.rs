@@ -1,22 +1,37 @@
+mod admin;
+mod auth;
 mod handler;
+mod legacy;
 mod logging;
+mod rest;
 mod router;
+mod store;
+mod tls;
 mod ws;
 
-use crate::logging::{log_msg, setup_app_dir};
+use crate::auth::Session;
+use crate::legacy::LegacyIdMap;
+use crate::logging::{migrate_legacy_logs, setup_app_dir, LogEntry, Logger, RotationLimits};
 use crate::router::Router;
+use crate::store::{state_path, FileStore, StateStore};
+use chatter::common::config::Config;
+use chatter::common::protocol;
 use chatter::common::*;
 use hyper::{
     body::to_bytes,
+    server::accept::{self, Accept},
+    server::conn::AddrIncoming,
     service::{make_service_fn, service_fn},
     Body, Request, Server,
 };
 use route_recognizer::Params;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use rustls::ServerConfig;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
-use std::{thread, time};
+use std::time;
+use tokio::io::{AsyncRead, AsyncWrite};
 use warp::{Filter, Rejection};
 
 type Response = hyper::Response<Body>;
@@ -25,51 +40,251 @@ type ResultWS<T> = Result<T, Rejection>;
 
 type ClientMap = HashMap<ClientUuid, Client>;
 type RoomMap = HashMap<RoomUuid, Room>;
+type UserMap = HashMap<ClientUuid, User>;
+type SessionMap = HashMap<SessionToken, Session>;
+
+const MIGRATE_LOGS_CMD: &str = "migrate-logs";
 
 pub struct AppState {
     pub name: String,
     pub routing_map: Arc<Router>,
+    pub users: UserMap,
     pub clients: ClientMap,
     pub rooms: RoomMap,
+    pub sessions: SessionMap,
+    /// Ids of the users registered by v0 connections still open
+    pub legacy_ids: LegacyIdMap,
+    pub logger: Logger,
+    pub config: Config,
+    store: Box<dyn StateStore>,
 }
 
 impl AppState {
-    fn new() -> Arc<Mutex<Self>> {
+    fn new(config: Config, store: Box<dyn StateStore>, logger: Logger) -> Arc<Mutex<Self>> {
+        let stored_state = store.load().expect("Loading server state failed!");
+        let users = stored_state
+            .users
+            .into_iter()
+            .map(|u| (u.uuid, User::new(&u.name.0, u.password_hash)))
+            .collect::<UserMap>();
+        let rooms = stored_state
+            .rooms
+            .into_iter()
+            .map(|r| (r.uuid, r.into_room()))
+            .collect::<RoomMap>();
+        println!("Loaded {} users and {} rooms", users.len(), rooms.len());
+        // Users and rooms created before names were unique may share one
+        let mut user_names = HashSet::new();
+        for user in users.values() {
+            if !user_names.insert(user.name.normalized()) {
+                eprintln!("Warning: more than one user is named '{}'", user.name.0);
+            }
+        }
+        let mut room_names = HashSet::new();
+        for room in rooms.values() {
+            if !room_names.insert(room.name.normalized()) {
+                eprintln!("Warning: more than one room is named '{}'", room.name.0);
+            }
+        }
+
         Arc::new(Mutex::new(AppState {
             name: "Pre-websocket server".to_string(),
+            users,
             clients: ClientMap::new(),
-            rooms: RoomMap::new(),
+            rooms,
+            sessions: SessionMap::new(),
+            legacy_ids: LegacyIdMap::new(),
+            logger,
+            config,
+            store,
             routing_map: {
                 let mut router: Router = Router::new();
                 router.get(
                     HEALTH_CHECK_ENDPOINT,
                     Box::new(handler::handle_health_check),
                 );
+                router.post(HELLO_ENDPOINT, Box::new(handler::handle_hello));
                 router.post(SEND_MSG_ENDPOINT, Box::new(handler::handle_send_msg));
                 router.post(LEAVE_ROOM_ENDPOINT, Box::new(handler::handle_leave_room));
                 router.post(EXIT_APP_ENDPOINT, Box::new(handler::handle_exit_app));
                 router.post(LOGIN_ENDPOINT, Box::new(handler::handle_login));
                 router.post(GET_ROOM_ENDPOINT, Box::new(handler::handle_get_room));
                 router.post(CREATE_ROOM_ENDPOINT, Box::new(handler::handle_create_room));
+                router.post(
+                    GET_OR_CREATE_ROOM_ENDPOINT,
+                    Box::new(handler::handle_get_or_create_room),
+                );
                 router.post(JOIN_ROOM_ENDPOINT, Box::new(handler::handle_join_room));
                 router.post(HEARTBEAT_ENDPOINT, Box::new(handler::handle_heartbeat));
+                router.post(
+                    LOAD_HISTORY_ENDPOINT,
+                    Box::new(handler::handle_load_history),
+                );
+                router.post(LIST_ROOMS_ENDPOINT, Box::new(handler::handle_list_rooms));
+                router.post(
+                    DESCRIBE_ROOM_ENDPOINT,
+                    Box::new(handler::handle_describe_room),
+                );
+                router.post(
+                    LIST_MEMBERS_ENDPOINT,
+                    Box::new(handler::handle_list_members),
+                );
+                router.post(RENAME_ROOM_ENDPOINT, Box::new(handler::handle_rename_room));
+                router.post(DELETE_ROOM_ENDPOINT, Box::new(handler::handle_delete_room));
+                router.post(DIRECT_MSG_ENDPOINT, Box::new(handler::handle_direct_msg));
+                router.post(
+                    SET_ROOM_PRIVATE_ENDPOINT,
+                    Box::new(handler::handle_set_room_private),
+                );
+                router.post(
+                    SET_ROOM_PASSWORD_ENDPOINT,
+                    Box::new(handler::handle_set_room_password),
+                );
+                router.post(INVITE_ENDPOINT, Box::new(handler::handle_invite));
+                router.post(UNINVITE_ENDPOINT, Box::new(handler::handle_uninvite));
+                router.post(KICK_ENDPOINT, Box::new(handler::handle_kick));
+                router.post(BAN_ENDPOINT, Box::new(handler::handle_ban));
+                router.post(MUTE_ENDPOINT, Box::new(handler::handle_mute));
+                router.post(SET_ROLE_ENDPOINT, Box::new(handler::handle_set_role));
+                router.post(
+                    SET_RETENTION_ENDPOINT,
+                    Box::new(handler::handle_set_retention),
+                );
+                router.get(ROOMS_ENDPOINT, Box::new(rest::handle_list_rooms));
+                router.get(ROOM_ENDPOINT, Box::new(rest::handle_get_room));
+                router.delete(ROOM_ENDPOINT, Box::new(rest::handle_delete_room));
+                router.get(ROOM_MEMBERS_ENDPOINT, Box::new(rest::handle_list_members));
+                router.get(ROOM_MESSAGES_ENDPOINT, Box::new(rest::handle_list_messages));
+                router.post(ROOM_MESSAGES_ENDPOINT, Box::new(rest::handle_post_message));
+                router.delete(ROOM_MEMBER_ENDPOINT, Box::new(rest::handle_remove_member));
+                router.post(
+                    USER_MESSAGES_ENDPOINT,
+                    Box::new(rest::handle_post_direct_message),
+                );
+                router.get(ADMIN_CLIENTS_ENDPOINT, Box::new(admin::handle_list_clients));
+                router.delete(
+                    ADMIN_CLIENT_ENDPOINT,
+                    Box::new(admin::handle_disconnect_client),
+                );
+                router.get(ADMIN_ROOMS_ENDPOINT, Box::new(admin::handle_list_rooms));
+                router.delete(ADMIN_ROOM_ENDPOINT, Box::new(admin::handle_delete_room));
+                router.post(
+                    ADMIN_ANNOUNCEMENTS_ENDPOINT,
+                    Box::new(admin::handle_announce),
+                );
+                router.post(
+                    ADMIN_RELOAD_CONFIG_ENDPOINT,
+                    Box::new(admin::handle_reload_config),
+                );
+                router.post(
+                    ADMIN_PASSWORDS_ENDPOINT,
+                    Box::new(admin::handle_set_password),
+                );
+                router.upgrade(WS_ENDPOINT, Box::new(handler::handle_ws_upgrade));
                 Arc::new(router)
             },
         }))
     }
 
-    fn send_to_room(&self, msg: &ChatMessage, room_uuid: RoomUuid) {
-        let msg_json = serde_json::to_string(&msg).unwrap();
+    /// Finds the user regardless of case, names being unique in that form
+    fn find_user_by_name(&self, client_name: &ClientName) -> Option<ClientUuid> {
+        let normalized = client_name.normalized();
+        self.users.iter().find_map(|(k, v)| {
+            if v.name.normalized() == normalized {
+                Some(*k)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Tells whether the name, or one only differing from it by case, is taken
+    fn is_name_taken(&self, client_name: &ClientName) -> bool {
+        let normalized = client_name.normalized();
+        self.users
+            .values()
+            .any(|user| user.name.normalized() == normalized)
+    }
+
+    fn find_room(&self, room_name: &RoomName) -> Option<RoomUuid> {
+        let normalized = room_name.normalized();
+        self.rooms.iter().find_map(|(k, v)| {
+            if v.name.normalized() == normalized {
+                Some(*k)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn open_session(&mut self, client_uuid: ClientUuid) -> SessionToken {
+        let (token, session) = Session::new(client_uuid);
+        self.sessions.insert(token.clone(), session);
+        token
+    }
+
+    /// Resolves the session token to its client, extending the session.
+    fn authenticate(&mut self, token: &SessionToken) -> Option<ClientUuid> {
+        match self.sessions.get_mut(token) {
+            Some(session) if !session.is_expired() => {
+                session.refresh();
+                Some(session.client_uuid)
+            }
+            _ => None,
+        }
+    }
+
+    fn close_session(&mut self, token: &SessionToken) {
+        self.sessions.remove(token);
+    }
+
+    fn remove_expired_sessions(&mut self) {
+        self.sessions.retain(|_, session| !session.is_expired());
+    }
+
+    fn persist_user(&mut self, client_uuid: ClientUuid) {
+        if let Some(user) = self.users.get(&client_uuid) {
+            self.store.save_user(client_uuid, user);
+        }
+    }
+
+    fn persist_room(&mut self, room_uuid: RoomUuid) {
+        if let Some(room) = self.rooms.get(&room_uuid) {
+            self.store.save_room(room);
+        }
+    }
+
+    fn log(&self, msg: &ChatMessage, room_uuid: RoomUuid) {
+        if let Err(e) = self.logger.log(msg, room_uuid) {
+            eprintln!("Error logging message for room {}: {}", room_uuid.0, e);
+        }
+    }
+
+    fn send_to_room(&self, event: ServerEvent, room_uuid: RoomUuid) {
         let room = self.rooms.get(&room_uuid).unwrap();
 
         for client_uuid in &room.members {
-            let msg_for_client = Ok(warp::ws::Message::text(msg_json.clone()));
-
             if let Some(client_conn) = self.clients.get(client_uuid) {
-                client_conn
-                    .sender
-                    .send(msg_for_client)
-                    .expect("Sending message failed!");
+                if client_conn.send_event(&event).is_err() {
+                    eprintln!("Error sending to client {}", client_uuid.0);
+                }
+            }
+        }
+    }
+
+    /// Sends the event to every connected client, in a room or not
+    fn broadcast(&self, event: ServerEvent) {
+        for (client_uuid, client_conn) in &self.clients {
+            if client_conn.send_event(&event).is_err() {
+                eprintln!("Error broadcasting to client {}", client_uuid.0);
+            }
+        }
+    }
+
+    fn send_to_client(&self, event: ServerEvent, client_uuid: ClientUuid) {
+        if let Some(client_conn) = self.clients.get(&client_uuid) {
+            if client_conn.send_event(&event).is_err() {
+                eprintln!("Error sending to client {}", client_uuid.0);
             }
         }
     }
@@ -92,35 +307,134 @@ impl AppState {
 
     fn remove(&mut self, client_uuid: ClientUuid) {
         self.clients.remove(&client_uuid);
+        self.legacy_ids
+            .retain(|_, legacy_id| legacy_id.client_uuid != client_uuid);
     }
 
     fn disconnect_client_from_one(&mut self, client_uuid: ClientUuid, room_uuid: RoomUuid) {
-        let goodbye_msg_content = format!(
-            "{} has left the chat",
-            &self.clients.get(&client_uuid).unwrap().name.0
-        );
-        let goodbye_msg = ChatMessage::new(SERVER_SIGNATURE, &*goodbye_msg_content);
-        self.send_to_room(&goodbye_msg, room_uuid);
+        let left = LogEntry::Left {
+            left: self.users[&client_uuid].name.clone(),
+            timestamp: chrono::Utc::now(),
+        };
+        self.record_presence(left, room_uuid);
         self.rooms.get_mut(&room_uuid).unwrap().remove(client_uuid);
-        if log_msg(&goodbye_msg, room_uuid).is_err() {
-            eprintln!("Error logging message for room {}", room_uuid.0);
+        self.persist_room(room_uuid);
+    }
+
+    /// Disconnects the client from every room, closes its connection and ends its sessions
+    fn force_disconnect(&mut self, client_uuid: ClientUuid) {
+        self.disconnect_client_from_all(client_uuid);
+        if let Some(client) = self.clients.get(&client_uuid) {
+            let reason = "Disconnected by an administrator".to_string();
+            let _ = client.send_event(&ServerEvent::Error(reason));
+            let _ = client.sender.send(Ok(warp::ws::Message::close()));
         }
+        self.remove(client_uuid);
+        self.sessions
+            .retain(|_, session| session.client_uuid != client_uuid);
     }
 
-    fn disconnect_client_from_all(&mut self, client_uuid: ClientUuid) {
-        let goodbye_msg_content = format!(
-            "{} has left the chat",
-            &self.clients.get(&client_uuid).unwrap().name.0
+    /// Applies the settings which can change while running.
+    /// Returns those which changed but only take effect after a restart.
+    fn reload_config(&mut self, mut config: Config) -> Vec<&'static str> {
+        let current = &self.config;
+        let restart_required = [
+            ("host", current.host != config.host),
+            ("http_port", current.http_port != config.http_port),
+            ("ws_port", current.ws_port != config.ws_port),
+            ("single_port", current.single_port != config.single_port),
+            ("tls", current.tls != config.tls),
+            ("tls_cert", current.tls_cert != config.tls_cert),
+            ("tls_key", current.tls_key != config.tls_key),
+            ("data_dir", current.data_dir != config.data_dir),
+            ("log_dir", current.log_dir() != config.log_dir()),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| key)
+        .collect();
+
+        config.host = current.host.clone();
+        config.http_port = current.http_port;
+        config.ws_port = current.ws_port;
+        config.single_port = current.single_port;
+        config.tls = current.tls;
+        config.tls_cert = current.tls_cert.clone();
+        config.tls_key = current.tls_key.clone();
+        config.data_dir = current.data_dir.clone();
+        config.log_dir = current.log_dir.clone();
+        if let Err(e) = self.logger.set_limits(RotationLimits::new(&config)) {
+            eprintln!("Error updating the log rotation limits: {}", e);
+        }
+        self.config = config;
+        restart_required
+    }
+
+    /// Logs the user joining or leaving the room and tells its members
+    fn record_presence(&self, entry: LogEntry, room_uuid: RoomUuid) {
+        if let Err(e) = self.logger.log_entry(&entry, room_uuid) {
+            eprintln!("Error logging entry for room {}: {}", room_uuid.0, e);
+        }
+        self.send_to_room(entry.into_event(room_uuid), room_uuid);
+    }
+
+    /// Logs the server's message to the room and shows it to its members
+    fn record_in_room(&self, contents: &str, room_uuid: RoomUuid) {
+        let msg = ChatMessage::new(SERVER_SIGNATURE, contents);
+        self.log(&msg, room_uuid);
+        self.send_to_room(ServerEvent::Message(room_uuid, msg), room_uuid);
+    }
+
+    /// Removes the client from the room, telling them why
+    fn evict(&mut self, client_uuid: ClientUuid, room_uuid: RoomUuid, reason: &str) {
+        let client_name = self.users[&client_uuid].name.clone();
+        self.send_to_client(
+            ServerEvent::Kicked(room_uuid, reason.to_string()),
+            client_uuid,
+        );
+        self.rooms.get_mut(&room_uuid).unwrap().remove(client_uuid);
+        self.persist_room(room_uuid);
+        let now = chrono::Utc::now();
+        self.send_to_room(
+            ServerEvent::UserLeft(room_uuid, client_name, now),
+            room_uuid,
         );
-        let goodbye_msg = ChatMessage::new(SERVER_SIGNATURE, &*goodbye_msg_content);
+    }
 
+    /// Removes the room after telling its members, and archives its logs
+    fn delete_room(&mut self, room_uuid: RoomUuid) {
+        self.delete_rooms(vec![room_uuid]);
+    }
+
+    /// Deletes the rooms, telling their members, and archives their logs all at once
+    fn delete_rooms(&mut self, room_uuids: Vec<RoomUuid>) {
+        for room_uuid in &room_uuids {
+            let room_name = self.rooms[room_uuid].name.clone();
+            self.send_to_room(ServerEvent::RoomDeleted(*room_uuid, room_name), *room_uuid);
+            self.rooms.remove(room_uuid);
+            self.store.delete_room(*room_uuid);
+        }
+        if let Err(e) = self.logger.archive_rooms(room_uuids) {
+            eprintln!("Error archiving logs of deleted rooms: {}", e);
+        }
+    }
+
+    /// Rooms without members which saw no activity for the given time
+    fn get_abandoned_rooms(&self, timeout: chrono::Duration) -> Vec<RoomUuid> {
+        let cutoff = chrono::Utc::now() - timeout;
+        self.rooms
+            .iter()
+            .filter(|(_, v)| {
+                v.members.is_empty() && v.last_activity.is_none_or(|time| time < cutoff)
+            })
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+    }
+
+    fn disconnect_client_from_all(&mut self, client_uuid: ClientUuid) {
         let client_rooms = self.get_client_rooms(client_uuid);
         for room in client_rooms {
-            self.send_to_room(&goodbye_msg, room);
-            self.rooms.get_mut(&room).unwrap().remove(client_uuid);
-            if log_msg(&goodbye_msg, room).is_err() {
-                eprintln!("Error logging message for room {}", room.0);
-            }
+            self.disconnect_client_from_one(client_uuid, room);
         }
     }
 }
@@ -142,31 +456,73 @@ impl Context {
         }
     }
 
+    async fn body(&mut self) -> Result<&hyper::body::Bytes, Error> {
+        if self.body_bytes.is_none() {
+            let body = to_bytes(self.req.body_mut()).await?;
+            self.body_bytes = Some(body);
+        }
+        Ok(self.body_bytes.as_ref().unwrap())
+    }
+
     pub async fn body_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, Error> {
-        let body_bytes = match self.body_bytes {
-            Some(ref v) => v,
-            _ => {
-                let body = to_bytes(self.req.body_mut()).await?;
-                self.body_bytes = Some(body);
-                self.body_bytes.as_ref().unwrap()
-            }
-        };
-        Ok(serde_json::from_slice(body_bytes)?)
+        Ok(serde_json::from_slice(self.body().await?)?)
+    }
+
+    /// Decodes the request body, in any supported version of the protocol
+    pub async fn body_request(&mut self) -> Result<protocol::Request, Error> {
+        Ok(protocol::decode_request(self.body().await?)?)
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let app = AppState::new();
+    let (mut config, args) = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {:#}", e);
+        std::process::exit(1);
+    });
+    let migrate_logs = args.first().map(String::as_str) == Some(MIGRATE_LOGS_CMD);
+    if !migrate_logs {
+        config.set_host_arg(&args).unwrap_or_else(|e| {
+            eprintln!("Invalid arguments: {:#}", e);
+            std::process::exit(1);
+        });
+    }
+
+    setup_app_dir(&config.data_dir, &config.log_dir()).expect("App's directory setup failed!");
+    if migrate_logs {
+        migrate_legacy_logs(&config.log_dir()).expect("Migrating room logs failed!");
+        return;
+    }
 
-    setup_app_dir().expect("App's directory setup failed!");
-    let http = tokio::spawn(run_http(app.clone()));
-    let ws = tokio::spawn(run_ws(app.clone()));
+    let tls_config = tls::load_server_config(&config).unwrap_or_else(|e| {
+        eprintln!("Invalid TLS configuration: {:#}", e);
+        std::process::exit(1);
+    });
+
+    let store = FileStore::open(state_path(&config.data_dir)).expect("Opening state store failed!");
+    let (logger, log_writer) = Logger::spawn(config.log_dir(), RotationLimits::new(&config));
+    let app = AppState::new(config, Box::new(store), logger.clone());
+
+    let http = tokio::spawn(run_http(app.clone(), tls_config.clone()));
+    let ws = tokio::spawn(run_ws(app.clone(), tls_config));
     let heartbeat = tokio::spawn(run_heartbeat_service(app.clone()));
+    let log_sweeper = tokio::spawn(run_log_sweeper(app.clone()));
+    let room_cleaner = tokio::spawn(run_room_cleaner(app.clone()));
+    let state_writer = tokio::spawn(run_state_writer(app.clone()));
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => println!("Shutting down..."),
+        res = heartbeat => res.expect("Heartbeat service died!"),
+        res = log_sweeper => res.expect("Log sweeper died!"),
+        res = room_cleaner => res.expect("Room cleaner died!"),
+        res = state_writer => res.expect("State writer died!"),
+        res = ws => res.expect("WS server died!"),
+        res = http => res.expect("HTTP server died!"),
+    }
 
-    heartbeat.await.expect("Heartbeat service died!");
-    ws.await.expect("WS server died!");
-    http.await.expect("HTTP server died!");
+    write_state(&app).await;
+    logger.shutdown().await;
+    log_writer.await.expect("Log writer died!");
 }
 
 async fn route_and_handle(
@@ -174,7 +530,7 @@ async fn route_and_handle(
     req_body: Request<Body>,
     app_state: Arc<Mutex<AppState>>,
 ) -> Result<Response, Error> {
-    let found_handler = router.route(req_body.uri().path(), req_body.method());
+    let found_handler = router.route(&req_body);
     let resp = found_handler
         .handler
         .invoke(Context::new(app_state, req_body, found_handler.params))
@@ -183,12 +539,14 @@ async fn route_and_handle(
 }
 
 async fn run_heartbeat_service(app: Arc<Mutex<AppState>>) {
-    const KILL_TIMEOUT: u64 = 5000;
     println!("Heartbeat service running!");
 
     loop {
-        thread::sleep(time::Duration::from_millis(KILL_TIMEOUT));
+        // Read on every round, as the config can be reloaded
+        let kill_timeout = app.lock().unwrap().config.kill_timeout_ms;
+        tokio::time::sleep(time::Duration::from_millis(kill_timeout)).await;
         let dead_clients = app.lock().unwrap().get_dead_clients();
+        app.lock().unwrap().remove_expired_sessions();
 
         app.lock()
             .unwrap()
@@ -208,27 +566,141 @@ async fn run_heartbeat_service(app: Arc<Mutex<AppState>>) {
     }
 }
 
+async fn run_log_sweeper(app: Arc<Mutex<AppState>>) {
+    println!("Log sweeper running!");
+
+    loop {
+        // Read on every round, so that reloading the config changes it
+        let interval = app.lock().unwrap().config.log_sweep_interval_ms;
+        tokio::time::sleep(time::Duration::from_millis(interval)).await;
+        let (logger, policies, default_policy) = {
+            let app = app.lock().unwrap();
+            let default_policy = app.config.default_retention();
+            let policies = app
+                .rooms
+                .values()
+                .map(|room| (room.uuid, room.retention.unwrap_or(default_policy)))
+                .collect::<Vec<_>>();
+            (app.logger.clone(), policies, default_policy)
+        };
+        // Conversations have no policy of their own
+        logger.sweep(policies, default_policy).await;
+    }
+}
+
+async fn run_room_cleaner(app: Arc<Mutex<AppState>>) {
+    const CLEANUP_INTERVAL: u64 = 60 * 1000;
+    println!("Room cleaner running!");
+
+    loop {
+        // Read on every round, as the config can be reloaded
+        let timeout = app.lock().unwrap().config.empty_room_timeout_ms;
+        let interval = match timeout {
+            0 => CLEANUP_INTERVAL, // empty rooms are kept
+            timeout => CLEANUP_INTERVAL.min(timeout),
+        };
+        tokio::time::sleep(time::Duration::from_millis(interval)).await;
+        let mut app = app.lock().unwrap();
+        let timeout = app.config.empty_room_timeout_ms;
+        if timeout == 0 {
+            continue;
+        }
+        let abandoned = app.get_abandoned_rooms(chrono::Duration::milliseconds(timeout as i64));
+        if abandoned.is_empty() {
+            continue;
+        }
+        for room_uuid in &abandoned {
+            println!("Deleting empty room {}", room_uuid.0);
+        }
+        app.delete_rooms(abandoned);
+    }
+}
+
+/// Writes the changes made to the state in batches, so requests never wait on the disk
+async fn run_state_writer(app: Arc<Mutex<AppState>>) {
+    const WRITE_INTERVAL: u64 = 500;
+    println!("State writer running!");
+
+    loop {
+        tokio::time::sleep(time::Duration::from_millis(WRITE_INTERVAL)).await;
+        write_state(&app).await;
+    }
+}
+
+async fn write_state(app: &Arc<Mutex<AppState>>) {
+    let snapshot = match app.lock().unwrap().store.take_snapshot() {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+    match tokio::task::spawn_blocking(move || snapshot.write()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            eprintln!("Error persisting server state: {}", e);
+            app.lock().unwrap().store.mark_dirty();
+        }
+        Err(_) => eprintln!("State writer panicked!"),
+    }
+}
+
 fn build_addr(addr_str: String) -> SocketAddr {
     addr_str
-        .as_str()
-        .parse::<SocketAddr>()
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
         .expect("Address creation failed!")
 }
 
-async fn run_ws(app: Arc<Mutex<AppState>>) {
-    let addr = build_addr(get_addr_str(Protocol::WS));
+async fn run_ws(app: Arc<Mutex<AppState>>, tls_config: Option<Arc<ServerConfig>>) {
+    let (addr, single_port) = {
+        let config = &app.lock().unwrap().config;
+        (build_addr(config.ws_addr()), config.single_port)
+    };
+    if single_port {
+        println!("WS served by the HTTP server under {}", WS_ENDPOINT);
+        return std::future::pending().await;
+    }
 
-    let ws_route = warp::ws()
-        .and(warp::any().map(move || app.clone()))
-        .and_then(handler::handle_registration);
+    let routes = ws::ws_route(app).with(warp::cors().allow_any_origin());
+    match tls_config {
+        None => {
+            println!("WS open on {}", addr);
+            warp::serve(routes).run(addr).await;
+        }
+        Some(tls_config) => {
+            let incoming = tls::incoming(addr, tls_config)
+                .await
+                .expect("Binding WS listener failed!");
+            println!("WSS open on {}", addr);
+            warp::serve(routes).run_incoming(incoming).await;
+        }
+    }
+}
 
-    let routes = ws_route.with(warp::cors().allow_any_origin());
-    println!("WS open on {}", addr);
-    warp::serve(routes).run(addr).await;
+async fn run_http(app: Arc<Mutex<AppState>>, tls_config: Option<Arc<ServerConfig>>) {
+    let addr = build_addr(app.lock().unwrap().config.http_addr());
+    match tls_config {
+        None => {
+            let incoming = AddrIncoming::bind(&addr).expect("Binding HTTP listener failed!");
+            println!("HTTP open on {}", addr);
+            serve_http(incoming, app).await;
+        }
+        Some(tls_config) => {
+            let incoming = tls::incoming(addr, tls_config)
+                .await
+                .expect("Binding HTTP listener failed!");
+            println!("HTTPS open on {}", addr);
+            serve_http(accept::from_stream(incoming), app).await;
+        }
+    }
 }
 
-async fn run_http(app: Arc<Mutex<AppState>>) {
-    let new_service = make_service_fn(move |_| {
+async fn serve_http<I>(incoming: I, app: Arc<Mutex<AppState>>)
+where
+    I: Accept,
+    I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Error>,
+{
+    let new_service = make_service_fn(move |_: &I::Conn| {
         let app_capture = app.clone();
         async {
             Ok::<_, Error>(service_fn(move |req| {
@@ -238,9 +710,5 @@ async fn run_http(app: Arc<Mutex<AppState>>) {
         }
     });
 
-    let addr = build_addr(get_addr_str(Protocol::HTTP));
-    let server = Server::bind(&addr).serve(new_service);
-
-    println!("HTTP open on {}", addr);
-    let _ = server.await;
+    let _ = Server::builder(incoming).serve(new_service).await;
 }
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chatter::common::{ClientName, ClientUuid, RetentionPolicy, Room, RoomName, RoomUuid, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const STATE_FILE: &str = "state.json";
+
+pub fn state_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(STATE_FILE)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredUser {
+    pub uuid: ClientUuid,
+    pub name: ClientName,
+    /// Users persisted before passwords were introduced have none, until they log in again
+    #[serde(default)]
+    pub password_hash: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredRoom {
+    pub uuid: RoomUuid,
+    pub name: RoomName,
+    pub members: Vec<ClientUuid>,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub owner: Option<ClientUuid>,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub invited: Vec<ClientUuid>,
+    #[serde(default)]
+    pub moderators: Vec<ClientUuid>,
+    #[serde(default)]
+    pub bans: Vec<(ClientUuid, DateTime<Utc>)>,
+    #[serde(default)]
+    pub mutes: Vec<(ClientUuid, DateTime<Utc>)>,
+    /// As of the last time the room was saved, which not every message does
+    #[serde(default)]
+    pub last_activity: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct StoredState {
+    pub users: Vec<StoredUser>,
+    pub rooms: Vec<StoredRoom>,
+}
+
+impl StoredRoom {
+    fn from_room(room: &Room) -> Self {
+        StoredRoom {
+            uuid: room.uuid,
+            name: room.name.clone(),
+            members: room.members.iter().copied().collect(),
+            retention: room.retention,
+            description: room.description.clone(),
+            owner: room.owner,
+            private: room.private,
+            password_hash: room.password_hash.clone(),
+            invited: room.invited.iter().copied().collect(),
+            moderators: room.moderators.iter().copied().collect(),
+            bans: room.bans.iter().map(|(k, v)| (*k, *v)).collect(),
+            mutes: room.mutes.iter().map(|(k, v)| (*k, *v)).collect(),
+            last_activity: room.last_activity,
+        }
+    }
+
+    pub fn into_room(self) -> Room {
+        Room {
+            name: self.name,
+            uuid: self.uuid,
+            members: self.members.into_iter().collect(),
+            retention: self.retention,
+            description: self.description,
+            owner: self.owner,
+            private: self.private,
+            password_hash: self.password_hash,
+            invited: self.invited.into_iter().collect(),
+            moderators: self.moderators.into_iter().collect(),
+            bans: self.bans.into_iter().collect(),
+            mutes: self.mutes.into_iter().collect(),
+            // Rooms saved before activity was tracked are only cleaned up once idle from now on
+            last_activity: self.last_activity.or_else(|| Some(Utc::now())),
+        }
+    }
+}
+
+/// Persistence layer for users, rooms and room memberships.
+/// Every change to the server's state is recorded in the store, which batches them: they are
+/// only written out when the server takes a snapshot of the pending changes.
+pub trait StateStore: Send {
+    fn load(&self) -> io::Result<StoredState>;
+    fn save_user(&mut self, uuid: ClientUuid, user: &User);
+    fn save_room(&mut self, room: &Room);
+    fn delete_room(&mut self, room_uuid: RoomUuid);
+    /// Returns the state to write if it changed since the last snapshot.
+    fn take_snapshot(&mut self) -> Option<StateSnapshot>;
+    /// Marks the state as changed again, e.g. after writing a snapshot failed.
+    fn mark_dirty(&mut self);
+}
+
+/// Serialized state, written without holding on to the store.
+pub struct StateSnapshot {
+    path: PathBuf,
+    bytes: Vec<u8>,
+}
+
+impl StateSnapshot {
+    /// Replaces the state file atomically (write to a temporary file + rename).
+    pub fn write(&self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, &self.bytes)?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+/// Embedded store keeping the whole state in a single JSON file.
+pub struct FileStore {
+    path: PathBuf,
+    users: HashMap<ClientUuid, StoredUser>,
+    rooms: HashMap<RoomUuid, StoredRoom>,
+    dirty: bool,
+}
+
+impl FileStore {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut store = FileStore {
+            path,
+            users: HashMap::new(),
+            rooms: HashMap::new(),
+            dirty: false,
+        };
+        let state = store.load()?;
+        store.users = state.users.into_iter().map(|u| (u.uuid, u)).collect();
+        store.rooms = state.rooms.into_iter().map(|r| (r.uuid, r)).collect();
+        Ok(store)
+    }
+}
+
+#[derive(Serialize)]
+struct StoredStateRef<'a> {
+    users: Vec<&'a StoredUser>,
+    rooms: Vec<&'a StoredRoom>,
+}
+
+impl StateStore for FileStore {
+    fn load(&self) -> io::Result<StoredState> {
+        if !self.path.exists() {
+            return Ok(StoredState::default());
+        }
+        let bytes = fs::read(&self.path)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    fn save_user(&mut self, uuid: ClientUuid, user: &User) {
+        self.users.insert(
+            uuid,
+            StoredUser {
+                uuid,
+                name: user.name.clone(),
+                password_hash: user.password_hash.clone(),
+            },
+        );
+        self.dirty = true;
+    }
+
+    fn save_room(&mut self, room: &Room) {
+        self.rooms.insert(room.uuid, StoredRoom::from_room(room));
+        self.dirty = true;
+    }
+
+    fn delete_room(&mut self, room_uuid: RoomUuid) {
+        self.rooms.remove(&room_uuid);
+        self.dirty = true;
+    }
+
+    fn take_snapshot(&mut self) -> Option<StateSnapshot> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        let state = StoredStateRef {
+            users: self.users.values().collect(),
+            rooms: self.rooms.values().collect(),
+        };
+        Some(StateSnapshot {
+            path: self.path.clone(),
+            bytes: serde_json::to_vec_pretty(&state).expect("Serializing state failed!"),
+        })
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use chatter::common::config::Config;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+
+const HANDSHAKE_QUEUE_SIZE: usize = 32;
+/// Clients which don't complete the handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Accepting fails e.g. when out of file descriptors, so it backs off instead of spinning
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Builds the listeners' TLS settings, if TLS is enabled.
+pub fn load_server_config(config: &Config) -> anyhow::Result<Option<Arc<ServerConfig>>> {
+    if !config.tls {
+        return Ok(None);
+    }
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => bail!("tls requires both tls_cert and tls_key"),
+    };
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    Ok(Some(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("cannot open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("invalid certificate file {:?}", path))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {:?}", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("cannot open {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("invalid key file {:?}", path))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key found in {:?}", path),
+        }
+    }
+}
+
+/// Accepts TLS connections on the address, as a stream both hyper and warp can serve.
+pub async fn incoming(
+    addr: SocketAddr,
+    server_config: Arc<ServerConfig>,
+) -> io::Result<ReceiverStream<io::Result<TlsStream<TcpStream>>>> {
+    let listener = TcpListener::bind(addr).await?;
+    Ok(accept_tls(listener, server_config))
+}
+
+/// Handshakes run in their own tasks, so that a slow client does not hold up the others.
+fn accept_tls(
+    listener: TcpListener,
+    server_config: Arc<ServerConfig>,
+) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+    let acceptor = TlsAcceptor::from(server_config);
+    let (sender, receiver) = mpsc::channel(HANDSHAKE_QUEUE_SIZE);
+
+    tokio::spawn(async move {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Error accepting connection: {}", e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_ACCEPT_BACKOFF;
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        let _ = sender.send(Ok(tls_stream)).await;
+                    }
+                    Ok(Err(e)) => eprintln!("TLS handshake with {} failed: {}", peer_addr, e),
+                    Err(_) => eprintln!("TLS handshake with {} timed out", peer_addr),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tokio_stream::StreamExt;
+
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn loads_sec1_keys() {
+        let config = Config {
+            tls: true,
+            tls_cert: Some(test_file("localhost.crt")),
+            tls_key: Some(test_file("localhost.key")),
+            ..Config::default()
+        };
+        assert!(load_server_config(&config).unwrap().is_some());
+
+        let no_key = Config {
+            tls_key: Some(test_file("ca.crt")),
+            ..config
+        };
+        assert!(load_server_config(&no_key).is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_tls_past_stalled_handshakes() {
+        let server_config = load_server_config(&Config {
+            tls: true,
+            tls_cert: Some(test_file("localhost.crt")),
+            tls_key: Some(test_file("localhost.key")),
+            ..Config::default()
+        })
+        .unwrap()
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = accept_tls(listener, server_config);
+
+        // Never starts its handshake
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&test_file("ca.crt")).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(server_name, stream).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut server_stream = incoming.next().await.unwrap().unwrap();
+        let mut received = Vec::new();
+        server_stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        client.await.unwrap();
+    }
+}
@@ -1,15 +1,43 @@
 use futures::{FutureExt, StreamExt};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
-use warp::ws::WebSocket;
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Rejection, Reply};
 
-use chatter::common::{Client, ClientUuid, ReqData};
+use chatter::common::protocol::{
+    self, LegacyReqData, Request, VersionedReq, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use chatter::common::{
+    ApiError, Client, ClientName, ClientUuid, ErrorCode, Password, ReplyData, ReqData, ServerEvent,
+    SessionToken, User, WSSender, WsReply, WsRequest,
+};
 
+use crate::auth::hash_password_async;
+use crate::handler;
+use crate::legacy;
 use crate::AppState;
 use crate::Arc;
 use crate::Mutex;
 
+/// How many requests of a connection are handled at the same time, the next ones waiting for a slot
+const MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// Accepts WebSocket connections, both on the WS port and under `/ws` on the HTTP port.
+pub fn ws_route(
+    app: Arc<Mutex<AppState>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::ws()
+        .and(warp::any().map(move || app.clone()))
+        .and_then(handler::handle_registration)
+}
+
+/// Serves a WS connection until it closes.
+///
+/// Besides the plain `RegistrationData`/`ConnectData` frame attaching the connection to a user,
+/// any request can be sent as a `WsRequest` frame, which is answered with a `WsReply`.
+/// Clients negotiate the protocol with a `HelloData` request; those which don't speak v0,
+/// unless they send requests of a later format without an id.
 pub async fn new_client_connection(ws: WebSocket, app: Arc<Mutex<AppState>>) {
     let (client_ws_sender, mut client_ws_rcv) = ws.split();
     let (client_sender, client_rcv) = mpsc::unbounded_channel();
@@ -23,27 +51,227 @@ pub async fn new_client_connection(ws: WebSocket, app: Arc<Mutex<AppState>>) {
         result
     }));
 
-    if let Some(result) = client_ws_rcv.next().await {
+    let mut conn = Connection {
+        app,
+        sender: client_sender,
+        protocol_version: LEGACY_PROTOCOL_VERSION,
+    };
+    let request_slots = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    while let Some(result) = client_ws_rcv.next().await {
         let msg = match result {
             Ok(msg) => msg,
-            Err(_) => return,
+            Err(_) => break,
         };
+        if msg.is_close() {
+            break;
+        }
         let msg_json = match msg.to_str() {
             Ok(msg_json) => msg_json,
-            Err(_) => return,
+            Err(_) => continue,
+        };
+
+        if let Ok(WsRequest { id, data }) = serde_json::from_str(msg_json) {
+            if let ReqData::HelloData(version, capabilities) = data {
+                // Answered right away, as it changes how the next requests are handled
+                let data = conn.hello(version, &capabilities);
+                conn.reply(id, data);
+                continue;
+            }
+            // Each request gets a task of its own, so that slow ones (e.g. hashing passwords)
+            // don't hold up the connection
+            let slot = request_slots.clone().acquire_owned().await.unwrap();
+            let conn = conn.clone();
+            tokio::spawn(async move {
+                let data = conn.handle_request(data).await;
+                conn.reply(id, data);
+                drop(slot);
+            });
+            continue;
+        }
+        let data = match protocol::decode_request(msg_json.as_bytes()) {
+            Err(e) => {
+                eprintln!("Invalid client registration request: {}", e);
+                conn.send_error(format!("Invalid request: {}", e));
+                continue;
+            }
+            Ok(Request::Legacy(LegacyReqData::RegistrationData(name))) => {
+                if let Err(e) = conn.register_legacy(name) {
+                    conn.send_error(format!("Registration failed: {}", e.message));
+                }
+                continue;
+            }
+            Ok(Request::Legacy(_)) => {
+                eprintln!("Invalid client registration request");
+                conn.send_error("Only registrations can be sent over WS".to_string());
+                continue;
+            }
+            // Clients of before the negotiation expect events in envelopes already
+            Ok(Request::Unversioned(data)) => {
+                conn.protocol_version = PROTOCOL_VERSION;
+                data
+            }
+            Ok(Request::Versioned(VersionedReq { version, data })) => {
+                conn.protocol_version = version;
+                data
+            }
         };
-        match serde_json::from_str(msg_json) {
-            Err(e) => eprintln!("Invalid client registration request: {}", e),
-            Ok(v) => match v {
-                ReqData::RegistrationData(name) => {
-                    let new_client = Client::new(client_sender, &*name.0);
-                    app.lock()
-                        .unwrap()
-                        .clients
-                        .insert(ClientUuid(Uuid::new_v4()), new_client);
+        match data {
+            ReqData::RegistrationData(name, password) => {
+                if let Err(e) = conn.register(name, password).await {
+                    conn.send_error(format!("Registration failed: {}", e.message));
                 }
-                _ => eprintln!("Invalid client registration request"),
-            },
+            }
+            ReqData::ConnectData(token) => {
+                if conn.connect(&token).is_err() {
+                    conn.send_error("Connection failed: bad session token".to_string());
+                }
+            }
+            _ => {
+                eprintln!("Invalid client registration request");
+                conn.send_error(
+                    "Only registration and connection requests can be sent without an id"
+                        .to_string(),
+                );
+            }
         }
     }
+    conn.close();
+}
+
+#[derive(Clone)]
+struct Connection {
+    app: Arc<Mutex<AppState>>,
+    sender: WSSender,
+    protocol_version: u32,
+}
+
+impl Connection {
+    /// Forgets the clients attached to the connection once it's closed, and takes them out of
+    /// their rooms as if they had died
+    fn close(&self) {
+        let mut app = self.app.lock().unwrap();
+        let client_uuids = app
+            .clients
+            .iter()
+            .filter(|(_, client)| client.sender.same_channel(&self.sender))
+            .map(|(client_uuid, _)| *client_uuid)
+            .collect::<Vec<_>>();
+        for client_uuid in client_uuids {
+            app.disconnect_client_from_all(client_uuid);
+            app.remove(client_uuid);
+        }
+    }
+
+    fn send_error(&self, error: String) {
+        let event = ServerEvent::Error(error);
+        if let Some(frame) = protocol::encode_event(&event, self.protocol_version) {
+            let _ = self.sender.send(Ok(Message::text(frame)));
+        }
+    }
+
+    fn reply(&self, id: u64, data: ReplyData) {
+        let reply = serde_json::to_string(&WsReply { id, data }).unwrap();
+        let _ = self.sender.send(Ok(Message::text(reply)));
+    }
+
+    fn hello(&mut self, version: u32, capabilities: &[String]) -> ReplyData {
+        let (version, capabilities) = protocol::negotiate(version, capabilities);
+        self.protocol_version = version;
+        ReplyData::Hello(version, capabilities)
+    }
+
+    async fn handle_request(&self, req_data: ReqData) -> ReplyData {
+        let result = match req_data {
+            ReqData::RegistrationData(name, password) => self
+                .register(name, password)
+                .await
+                .map(|(client_uuid, token)| ReplyData::Registered(client_uuid, token)),
+            ReqData::ConnectData(token) => self.connect(&token).map(|_| ReplyData::Ok),
+            req_data => return handler::handle_ws_request(&self.app, req_data).await,
+        };
+        result.unwrap_or_else(ReplyData::Error)
+    }
+
+    /// Creates the user, attaches the connection to them and opens their session
+    async fn register(
+        &self,
+        name: ClientName,
+        password: Password,
+    ) -> Result<(ClientUuid, SessionToken), ApiError> {
+        // Checked first as well, so that clients trying to register existing users learn it quickly
+        if self.app.lock().unwrap().is_name_taken(&name) {
+            return Err(name_taken(&name));
+        }
+        name.validate().map_err(|reason| {
+            eprintln!("Invalid client registration request: {}", reason);
+            ApiError::new(ErrorCode::Invalid, reason)
+        })?;
+        // Hashing is slow on purpose, so it's done without holding the lock
+        let password_hash = hash_password_async(password).await.ok_or_else(|| {
+            eprintln!("Hashing password of {} failed", name.0);
+            ApiError::new(ErrorCode::Internal, "Registration failed")
+        })?;
+        let mut app = self.app.lock().unwrap();
+        if app.is_name_taken(&name) {
+            return Err(name_taken(&name));
+        }
+        let client_uuid = ClientUuid(Uuid::new_v4());
+        app.users
+            .insert(client_uuid, User::new(&name.0, password_hash));
+        app.persist_user(client_uuid);
+        let new_client = Client::new(self.sender.clone(), &name.0, self.protocol_version);
+        app.clients.insert(client_uuid, new_client);
+        Ok((client_uuid, app.open_session(client_uuid)))
+    }
+
+    /// Registers a new user without a password, as v0 clients have none to give, and attaches
+    /// the connection to them. Nothing proves that the connection is an existing user's, so
+    /// every name in use is refused.
+    fn register_legacy(&mut self, name: ClientName) -> Result<ClientUuid, ApiError> {
+        self.protocol_version = LEGACY_PROTOCOL_VERSION;
+        name.validate().map_err(|reason| {
+            eprintln!("Invalid client registration request: {}", reason);
+            ApiError::new(ErrorCode::Invalid, reason)
+        })?;
+        let mut app = self.app.lock().unwrap();
+        if app.is_name_taken(&name) {
+            return Err(name_taken(&name));
+        }
+        let client_uuid = ClientUuid(Uuid::new_v4());
+        app.users
+            .insert(client_uuid, User::new(&name.0, String::new()));
+        app.persist_user(client_uuid);
+        let new_client = Client::new(self.sender.clone(), &name.0, self.protocol_version);
+        app.clients.insert(client_uuid, new_client);
+        legacy::issue_id(&mut app, client_uuid);
+        Ok(client_uuid)
+    }
+
+    /// Attaches the connection to the session's user
+    fn connect(&self, token: &SessionToken) -> Result<ClientUuid, ApiError> {
+        let mut app = self.app.lock().unwrap();
+        match app.authenticate(token) {
+            Some(client_uuid) => {
+                let name = app.users[&client_uuid].name.0.clone();
+                let new_client = Client::new(self.sender.clone(), &name, self.protocol_version);
+                app.clients.insert(client_uuid, new_client);
+                Ok(client_uuid)
+            }
+            None => {
+                eprintln!("Invalid client connection request: bad session token");
+                Err(ApiError::new(
+                    ErrorCode::Unauthorized,
+                    "Invalid or expired session",
+                ))
+            }
+        }
+    }
+}
+
+fn name_taken(name: &ClientName) -> ApiError {
+    eprintln!("Invalid client registration request: {} is taken", name.0);
+    ApiError::new(
+        ErrorCode::AlreadyExists,
+        format!("The name '{}' is taken", name.0),
+    )
 }